@@ -3,8 +3,9 @@
 #include "util.h"
 #include "Inkplate.h"
 
-//#define ALWAYS_SHOW_BATTERY
-#define BATTERY_WARNING_LEVEL 3.6
+// The battery voltage is reported to the server, which renders a warning onto
+// the frame once it is low. Only if it is unable to, the warning is drawn by
+// the firmware.
 
 #ifdef ARDUINO_INKPLATECOLOR
 // Currently needed as the new port multiplexer is not supported by the default library yet:
@@ -54,32 +55,20 @@ double readBatteryVoltage()
 }
 #endif
 
-void ALWAYS_INLINE checkBattery(Inkplate *display)
+double readBatteryLevel(Inkplate *display)
 {
 #ifdef ARDUINO_INKPLATECOLOR
-  double batteryLevel = readBatteryVoltage();
+  return readBatteryVoltage();
 #else
-  double batteryLevel = display->readBattery();
+  return display->readBattery();
 #endif
-  Serial.print("Battery level: ");
-  Serial.println(batteryLevel);
-#ifndef ALWAYS_SHOW_BATTERY
-  if (batteryLevel != 0.0 && batteryLevel < BATTERY_WARNING_LEVEL)
-  {
-#endif
-    display->setTextColor(7, 0);
-    display->setCursor(0, E_INK_HEIGHT - 25);
-#ifdef ALWAYS_SHOW_BATTERY
-    display->print("Battery level: ");
-#else
+}
+
+void ALWAYS_INLINE drawBatteryWarning(Inkplate *display, double batteryLevel)
+{
+  display->setTextColor(7, 0);
+  display->setCursor(0, E_INK_HEIGHT - 25);
   display->print("Battery level low! (");
-#endif
-    display->print(batteryLevel);
-    display->print(" V");
-#ifndef ALWAYS_SHOW_BATTERY
-    display->println(")");
-#endif
-#ifndef ALWAYS_SHOW_BATTERY
-  }
-#endif
+  display->print(batteryLevel);
+  display->println(" V)");
 }
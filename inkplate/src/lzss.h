@@ -0,0 +1,59 @@
+#pragma once
+
+#include <Arduino.h>
+
+// Decoder of the LZSS variant the server compresses frames with, if they are
+// requested using `compression=lzss`.
+//
+// The data is a sequence of groups, each starting with a flag byte followed by
+// up to eight items. Starting with the least significant bit, a cleared flag
+// marks a literal byte, a set flag a back reference of three bytes: The
+// distance to the start of the match within the already decoded data as 16 bit
+// little endian, followed by the length of the match minus three.
+//
+// Returns the number of decoded bytes, or 0 if the data is invalid or does not
+// fit into the output buffer.
+size_t lzss_decode(const uint8_t *in, size_t in_size, uint8_t *out, size_t out_size)
+{
+  size_t in_pos = 0;
+  size_t out_pos = 0;
+
+  while (in_pos < in_size)
+  {
+    uint8_t flags = in[in_pos++];
+
+    for (uint8_t flag = 0; flag < 8 && in_pos < in_size; flag++)
+    {
+      if ((flags & (1 << flag)) == 0)
+      {
+        if (out_pos >= out_size)
+        {
+          return 0;
+        }
+        out[out_pos++] = in[in_pos++];
+        continue;
+      }
+
+      if (in_pos + 3 > in_size)
+      {
+        return 0;
+      }
+      size_t distance = in[in_pos] | (in[in_pos + 1] << 8);
+      size_t length = in[in_pos + 2] + 3;
+      in_pos += 3;
+
+      if (distance == 0 || distance > out_pos || out_pos + length > out_size)
+      {
+        return 0;
+      }
+      // Matches may overlap the data they produce, therefore they are copied
+      // byte by byte
+      for (size_t i = 0; i < length; i++, out_pos++)
+      {
+        out[out_pos] = out[out_pos - distance];
+      }
+    }
+  }
+
+  return out_pos;
+}
@@ -9,25 +9,50 @@
 #include "driver/rtc_io.h"
 
 #include "battery.h"
+#include "lzss.h"
 
 // WIFI config
 #include "wifi_config.h"
 
+// Version reported to the server along with the telemetry
+#define FIRMWARE_VERSION "0.5.0"
+
 // Conversion factor for micro seconds to seconds
 #define uS_TO_S_FACTOR 1000000
-// Time ESP32 will go to sleep (in seconds)
+// Time ESP32 will go to sleep (in seconds), unless the server asks for
+// another one
 #define TIME_TO_SLEEP 900
 // #define TIME_TO_SLEEP 10
 
-const char *request_url = "http://192.168.178.3:9000/lexica/inkplate";
+const char *request_url = "http://192.168.178.3:9000/lexica/inkplate?compression=lzss";
+// Header the server names the compression of the frame with
+const char *compression_header = "X-Inkplate-Compression";
+// Header the server names the seconds to sleep until the next update with
+const char *sleep_header = "X-Inkplate-Sleep";
+// Header the server asks to draw the battery warning with, if it could not
+// render it onto the frame
+const char *battery_warning_header = "X-Inkplate-Battery-Warning";
+// Header the device identifies itself to the server with, by its MAC address
+const char *device_header = "X-Inkplate-Device";
+// Headers the device reports its telemetry to the server with
+const char *battery_header = "X-Inkplate-Battery";
+const char *wake_reason_header = "X-Inkplate-Wake-Reason";
+const char *rssi_header = "X-Inkplate-Rssi";
+const char *firmware_header = "X-Inkplate-Firmware";
+
+// Entity tag of the frame currently shown. Kept in the RTC memory, which
+// survives deep sleep, to skip the download and refresh if the frame has not
+// changed.
+RTC_DATA_ATTR char frame_etag[64] = "";
 
 Inkplate display;
 
-size_t http_request(const char *url, byte *buffer, size_t buffer_size);
+size_t http_request(const char *url, byte *buffer, size_t buffer_size, double battery_level, bool *compressed, bool *not_modified, String *etag, uint64_t *sleep_seconds, bool *battery_warning);
 void render(uint8_t *raw_image, size_t nBytes);
 void setup_mcp();
 void goto_sleep(uint64_t);
 void log_wakeup_reason();
+const char *wakeup_reason_name();
 
 void setup()
 {
@@ -63,7 +88,41 @@ void setup()
     ESP.restart();
   }
 
-  size_t received = http_request(request_url, buffer, buffer_size);
+  // The server only sends compressed frames, which are smaller than the raw
+  // ones. Therefore the same amount of memory suffices for both.
+  byte *transfer_buffer = (byte *)ps_malloc(buffer_size);
+  if (transfer_buffer == nullptr)
+  {
+    log_d("Could not allocate memory for the transfer!");
+    ESP.restart();
+  }
+
+  bool compressed = false;
+  bool not_modified = false;
+  String etag;
+  uint64_t sleep_seconds = TIME_TO_SLEEP;
+  bool battery_warning = false;
+  double battery_level = readBatteryLevel(&display);
+  size_t received = http_request(request_url, transfer_buffer, buffer_size, battery_level, &compressed, &not_modified, &etag, &sleep_seconds, &battery_warning);
+  log_d("Sleeping for %llu seconds after this update", sleep_seconds);
+  if (not_modified)
+  {
+    log_d("Frame %s is still current, skipping refresh", frame_etag);
+    free(transfer_buffer);
+    free(buffer);
+    goto_sleep(sleep_seconds * uS_TO_S_FACTOR);
+  }
+
+  if (compressed)
+  {
+    log_d("Decompressing %d received bytes", received);
+    received = lzss_decode(transfer_buffer, received, buffer, buffer_size);
+  }
+  else
+  {
+    memcpy(buffer, transfer_buffer, received);
+  }
+  free(transfer_buffer);
 
   log_d("Received bytes %d, expected %d", received, buffer_size - 1);
 
@@ -71,6 +130,7 @@ void setup()
   {
     log_d("Rendering received image...");
     render(buffer, buffer_size - 1);
+    strlcpy(frame_etag, etag.c_str(), sizeof(frame_etag));
   }
   else
   {
@@ -86,10 +146,13 @@ void setup()
 
   free(buffer);
 
-  checkBattery(&display);
+  if (battery_warning)
+  {
+    drawBatteryWarning(&display, battery_level);
+  }
   display.display();
 
-  goto_sleep(TIME_TO_SLEEP * uS_TO_S_FACTOR);
+  goto_sleep(sleep_seconds * uS_TO_S_FACTOR);
 }
 
 void loop()
@@ -97,12 +160,23 @@ void loop()
   // Never reached because of sleep
 }
 
-size_t http_request(const char *url, byte *buffer, size_t buffer_size)
+size_t http_request(const char *url, byte *buffer, size_t buffer_size, double battery_level, bool *compressed, bool *not_modified, String *etag, uint64_t *sleep_seconds, bool *battery_warning)
 {
   HTTPClient http;
   size_t bytes_read = 0;
+  const char *header_keys[] = {compression_header, "ETag", sleep_header, battery_warning_header};
 
   http.begin(url);
+  http.collectHeaders(header_keys, 4);
+  http.addHeader(device_header, WiFi.macAddress());
+  http.addHeader(battery_header, String(battery_level, 3));
+  http.addHeader(wake_reason_header, wakeup_reason_name());
+  http.addHeader(rssi_header, String(WiFi.RSSI()));
+  http.addHeader(firmware_header, FIRMWARE_VERSION);
+  if (frame_etag[0] != '\0')
+  {
+    http.addHeader("If-None-Match", frame_etag);
+  }
   http.setConnectTimeout(3000);
   http.setTimeout(10000);
 
@@ -110,8 +184,21 @@ size_t http_request(const char *url, byte *buffer, size_t buffer_size)
   int httpCode = http.GET();
   if (httpCode > 0)
   {
-    if (httpCode == HTTP_CODE_OK)
+    long server_sleep_seconds = http.header(sleep_header).toInt();
+    if (server_sleep_seconds > 0)
     {
+      *sleep_seconds = server_sleep_seconds;
+    }
+
+    if (httpCode == HTTP_CODE_NOT_MODIFIED)
+    {
+      *not_modified = true;
+    }
+    else if (httpCode == HTTP_CODE_OK)
+    {
+      *compressed = http.header(compression_header) == "lzss";
+      *etag = http.header("ETag");
+      *battery_warning = http.header(battery_warning_header) == "true";
       int content_length = http.getSize();
       WiFiClient *stream = http.getStreamPtr();
       while (http.connected() && (content_length == -1 || bytes_read < content_length))
@@ -208,3 +295,23 @@ void log_wakeup_reason()
     break;
   }
 }
+
+// Short name of the wakeup reason, as reported to the server
+const char *wakeup_reason_name()
+{
+  switch (esp_sleep_get_wakeup_cause())
+  {
+  case ESP_SLEEP_WAKEUP_EXT0:
+    return "ext0";
+  case ESP_SLEEP_WAKEUP_EXT1:
+    return "ext1";
+  case ESP_SLEEP_WAKEUP_TIMER:
+    return "timer";
+  case ESP_SLEEP_WAKEUP_TOUCHPAD:
+    return "touchpad";
+  case ESP_SLEEP_WAKEUP_ULP:
+    return "ulp";
+  default:
+    return "reset";
+  }
+}
@@ -0,0 +1,76 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::ImageBuffer;
+
+use lexica_inkplate_server::dithering::{ColorMetric, DitheringAlgorithm, Palette};
+
+/// Resolution of the Inkplate 6COLOR
+const WIDTH: u32 = 600;
+const HEIGHT: u32 = 448;
+
+/// Smooth gradient with some texture, similar to typical images
+fn test_image() -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+    ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+        let texture = ((x * 7 + y * 13) % 32) as u8;
+        image::Rgba([
+            (x * 255 / WIDTH) as u8 ^ texture,
+            (y * 255 / HEIGHT) as u8,
+            (255 - (x + y) * 255 / (WIDTH + HEIGHT)) as u8 ^ texture,
+            255,
+        ])
+    })
+}
+
+fn bench_dithering(c: &mut Criterion) {
+    let image = test_image();
+    let mut group = c.benchmark_group("dithering");
+    group.sample_size(20);
+
+    let cases = [
+        (
+            DitheringAlgorithm::JarvisJudiceNinke,
+            ColorMetric::Euclidean,
+            false,
+        ),
+        (
+            DitheringAlgorithm::JarvisJudiceNinke,
+            ColorMetric::Euclidean,
+            true,
+        ),
+        (
+            DitheringAlgorithm::JarvisJudiceNinke,
+            ColorMetric::Ciede2000,
+            false,
+        ),
+        (
+            DitheringAlgorithm::FloydSteinberg,
+            ColorMetric::Redmean,
+            false,
+        ),
+        (DitheringAlgorithm::Bayer8, ColorMetric::Euclidean, false),
+        (DitheringAlgorithm::BlueNoise, ColorMetric::Cie76, false),
+    ];
+
+    for (algorithm, metric, serpentine) in cases {
+        let id = format!(
+            "{:?}/{:?}{}",
+            algorithm,
+            metric,
+            if serpentine { "/serpentine" } else { "" }
+        );
+        group.bench_function(BenchmarkId::from_parameter(id), |b| {
+            b.iter(|| {
+                algorithm.apply(
+                    image.clone(),
+                    Palette::AcepCalibrated.colors(),
+                    metric,
+                    serpentine,
+                )
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_dithering);
+criterion_main!(benches);
@@ -0,0 +1,410 @@
+use std::hash::{Hash, Hasher};
+use std::ops::RangeInclusive;
+
+use anyhow::anyhow;
+use image::{DynamicImage, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+/// Share of the darkest and brightest pixels of each channel, which are
+/// clipped by auto levels. Ignores a few outliers, which would otherwise
+/// prevent any stretching.
+const AUTO_LEVELS_CLIP: f32 = 0.005;
+
+/// Differences below this threshold are not sharpened by the unsharp mask
+const UNSHARP_THRESHOLD: i32 = 2;
+
+/// Accepted values of the adjustments. NaN lies outside of every range.
+const GAMMA_RANGE: RangeInclusive<f32> = 0.1..=5.0;
+const SATURATION_RANGE: RangeInclusive<f32> = 0.0..=4.0;
+const SHARPEN_RANGE: RangeInclusive<f32> = 0.0..=10.0;
+
+/// Adjustments applied to the cropped image, before it is dithered. The
+/// defaults leave the image unchanged.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Adjustments {
+    /// Stretch each channel to the full range
+    pub auto_levels: bool,
+    /// Equalize the histogram of the luminance
+    pub equalize: bool,
+    /// Values above 1.0 brighten the midtones, values below darken them
+    pub gamma: f32,
+    /// Factor applied to the saturation. 1.0 keeps the colors unchanged.
+    pub saturation: f32,
+    /// Radius (sigma) of the unsharp mask. 0.0 disables sharpening.
+    pub sharpen: f32,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Self {
+            auto_levels: false,
+            equalize: false,
+            gamma: 1.0,
+            saturation: 1.0,
+            sharpen: 0.0,
+        }
+    }
+}
+
+// Adjustments are part of the image request, which is used as key of the
+// prefetch queues. The values are compared by their exact representation.
+impl PartialEq for Adjustments {
+    fn eq(&self, other: &Self) -> bool {
+        self.auto_levels == other.auto_levels
+            && self.equalize == other.equalize
+            && self.gamma.to_bits() == other.gamma.to_bits()
+            && self.saturation.to_bits() == other.saturation.to_bits()
+            && self.sharpen.to_bits() == other.sharpen.to_bits()
+    }
+}
+
+impl Eq for Adjustments {}
+
+impl Hash for Adjustments {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.auto_levels.hash(state);
+        self.equalize.hash(state);
+        self.gamma.to_bits().hash(state);
+        self.saturation.to_bits().hash(state);
+        self.sharpen.to_bits().hash(state);
+    }
+}
+
+#[inline(always)]
+fn luminance(pixel: &[u8]) -> f32 {
+    0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32
+}
+
+#[inline(always)]
+fn to_channel(value: f32) -> u8 {
+    value.round().clamp(0.0, 255.0) as u8
+}
+
+/// Value below which the given share of all entries of the histogram lies
+fn histogram_percentile(histogram: &[usize; 256], share: f32) -> usize {
+    let total: usize = histogram.iter().sum();
+    let limit = (total as f32 * share) as usize;
+    let mut count = 0;
+    for (value, entries) in histogram.iter().enumerate() {
+        count += entries;
+        if count > limit {
+            return value;
+        }
+    }
+    255
+}
+
+fn auto_levels(image: &mut RgbaImage) {
+    let mut histograms = [[0usize; 256]; 3];
+    for pixel in image.pixels() {
+        for (histogram, value) in histograms.iter_mut().zip(pixel.data) {
+            histogram[value as usize] += 1;
+        }
+    }
+
+    let ranges = histograms.map(|histogram| {
+        (
+            histogram_percentile(&histogram, AUTO_LEVELS_CLIP) as f32,
+            histogram_percentile(&histogram, 1.0 - AUTO_LEVELS_CLIP) as f32,
+        )
+    });
+
+    for pixel in image.pixels_mut() {
+        for (value, (low, high)) in pixel.data.iter_mut().zip(ranges) {
+            if high > low {
+                *value = to_channel((*value as f32 - low) * 255.0 / (high - low));
+            }
+        }
+    }
+}
+
+fn equalize(image: &mut RgbaImage) {
+    let mut histogram = [0usize; 256];
+    for pixel in image.pixels() {
+        histogram[to_channel(luminance(&pixel.data)) as usize] += 1;
+    }
+
+    let total = (image.width() * image.height()) as f32;
+    let mut mapping = [0f32; 256];
+    let mut cumulative = 0;
+    for (value, entries) in histogram.iter().enumerate() {
+        cumulative += entries;
+        mapping[value] = cumulative as f32 * 255.0 / total;
+    }
+
+    // Shift all channels by the change of luminance, which keeps the hue
+    for pixel in image.pixels_mut() {
+        let current = luminance(&pixel.data);
+        let shift = mapping[to_channel(current) as usize] - current;
+        for value in pixel.data.iter_mut().take(3) {
+            *value = to_channel(*value as f32 + shift);
+        }
+    }
+}
+
+fn gamma(image: &mut RgbaImage, gamma: f32) {
+    let mut mapping = [0u8; 256];
+    for (value, mapped) in mapping.iter_mut().enumerate() {
+        *mapped = to_channel(255.0 * (value as f32 / 255.0).powf(1.0 / gamma));
+    }
+
+    for pixel in image.pixels_mut() {
+        for value in pixel.data.iter_mut().take(3) {
+            *value = mapping[*value as usize];
+        }
+    }
+}
+
+fn saturation(image: &mut RgbaImage, factor: f32) {
+    for pixel in image.pixels_mut() {
+        let gray = luminance(&pixel.data);
+        for value in pixel.data.iter_mut().take(3) {
+            *value = to_channel(gray + (*value as f32 - gray) * factor);
+        }
+    }
+}
+
+/// Unsharp mask, which adds the difference to the blurred image. The one of
+/// the image crate adds the absolute difference, brightening dark edges.
+fn unsharpen(image: &RgbaImage, sigma: f32) -> RgbaImage {
+    let blurred = image::imageops::blur(image, sigma);
+    let mut sharpened = image.clone();
+    for (pixel, blurred) in sharpened.pixels_mut().zip(blurred.pixels()) {
+        for (value, blurred) in pixel.data.iter_mut().zip(blurred.data).take(3) {
+            let difference = *value as i32 - blurred as i32;
+            if difference.abs() > UNSHARP_THRESHOLD {
+                *value = to_channel((*value as i32 + difference) as f32);
+            }
+        }
+    }
+    sharpened
+}
+
+impl Adjustments {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !GAMMA_RANGE.contains(&self.gamma) {
+            return Err(anyhow!(
+                "Gamma must be within {:?}: {}",
+                GAMMA_RANGE,
+                self.gamma
+            ));
+        }
+        if !SATURATION_RANGE.contains(&self.saturation) {
+            return Err(anyhow!(
+                "Saturation must be within {:?}: {}",
+                SATURATION_RANGE,
+                self.saturation
+            ));
+        }
+        if !SHARPEN_RANGE.contains(&self.sharpen) {
+            return Err(anyhow!(
+                "Sharpen radius must be within {:?}: {}",
+                SHARPEN_RANGE,
+                self.sharpen
+            ));
+        }
+        Ok(())
+    }
+
+    /// Apply all enabled adjustments in the order: auto levels, histogram
+    /// equalization, gamma, saturation and finally sharpening.
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let mut adjusted = image.to_rgba();
+
+        if self.auto_levels {
+            auto_levels(&mut adjusted);
+        }
+        if self.equalize {
+            equalize(&mut adjusted);
+        }
+        if self.gamma != 1.0 {
+            gamma(&mut adjusted, self.gamma);
+        }
+        if self.saturation != 1.0 {
+            saturation(&mut adjusted, self.saturation);
+        }
+        if self.sharpen > 0.0 {
+            adjusted = unsharpen(&adjusted, self.sharpen);
+        }
+
+        DynamicImage::ImageRgba8(adjusted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    fn gray_ramp() -> RgbaImage {
+        RgbaImage::from_fn(100, 1, |x, _| {
+            let value = 100 + x as u8 / 2;
+            Rgba([value, value, value, 255])
+        })
+    }
+
+    fn apply(adjustments: Adjustments, image: RgbaImage) -> RgbaImage {
+        adjustments
+            .apply(&DynamicImage::ImageRgba8(image))
+            .to_rgba()
+    }
+
+    #[test]
+    fn leaves_image_unchanged_by_default() {
+        let image = gray_ramp();
+        assert_eq!(
+            apply(Adjustments::default(), image.clone()).into_raw(),
+            image.into_raw()
+        );
+    }
+
+    #[test]
+    fn stretches_levels_to_full_range() {
+        let adjustments = Adjustments {
+            auto_levels: true,
+            ..Adjustments::default()
+        };
+        let adjusted = apply(adjustments, gray_ramp());
+        assert_eq!(adjusted.get_pixel(0, 0).data, [0, 0, 0, 255]);
+        assert_eq!(adjusted.get_pixel(99, 0).data, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn equalizes_luminance_keeping_the_hue() {
+        let image = RgbaImage::from_fn(100, 1, |x, _| {
+            let value = 100 + x as u8 / 2;
+            Rgba([value + 30, value, value - 30, 255])
+        });
+        let equalized = apply(
+            Adjustments {
+                equalize: true,
+                ..Adjustments::default()
+            },
+            image,
+        );
+
+        assert!(luminance(&equalized.get_pixel(0, 0).data) < 10.0);
+        assert!(luminance(&equalized.get_pixel(99, 0).data) > 245.0);
+        for pixel in equalized
+            .pixels()
+            .filter(|pixel| pixel.data[2] > 0 && pixel.data[0] < 255)
+        {
+            let [r, g, b, _] = pixel.data.map(i32::from);
+            assert!(
+                (r - g - 30).abs() <= 1 && (g - b - 30).abs() <= 1,
+                "{:?}",
+                pixel
+            );
+        }
+        let [r, g, b, _] = equalized.get_pixel(50, 0).data;
+        assert!(r > g && g > b);
+    }
+
+    fn step_edge() -> RgbaImage {
+        RgbaImage::from_fn(20, 20, |x, _| {
+            let value = if x < 10 { 100 } else { 150 };
+            Rgba([value, value, value, 255])
+        })
+    }
+
+    #[test]
+    fn sharpens_edges() {
+        let sharpened = apply(
+            Adjustments {
+                sharpen: 2.0,
+                ..Adjustments::default()
+            },
+            step_edge(),
+        );
+        assert!(sharpened.get_pixel(9, 10).data[0] < 100);
+        assert!(sharpened.get_pixel(10, 10).data[0] > 150);
+
+        let flat = RgbaImage::from_pixel(20, 20, Rgba([120, 80, 40, 255]));
+        let sharpened_flat = apply(
+            Adjustments {
+                sharpen: 2.0,
+                ..Adjustments::default()
+            },
+            flat.clone(),
+        );
+        assert_eq!(sharpened_flat.into_raw(), flat.into_raw());
+    }
+
+    #[test]
+    fn brightens_midtones_by_gamma() {
+        let image = RgbaImage::from_pixel(1, 1, Rgba([128, 0, 255, 255]));
+        let brightened = apply(
+            Adjustments {
+                gamma: 2.0,
+                ..Adjustments::default()
+            },
+            image.clone(),
+        );
+        // 255 * (128 / 255) ^ 0.5
+        assert_eq!(brightened.get_pixel(0, 0).data, [181, 0, 255, 255]);
+
+        let darkened = apply(
+            Adjustments {
+                gamma: 0.5,
+                ..Adjustments::default()
+            },
+            image,
+        );
+        // 255 * (128 / 255) ^ 2
+        assert_eq!(darkened.get_pixel(0, 0).data, [64, 0, 255, 255]);
+    }
+
+    #[test]
+    fn scales_saturation_around_luminance() {
+        let image = RgbaImage::from_pixel(1, 1, Rgba([200, 100, 100, 255]));
+        let desaturated = apply(
+            Adjustments {
+                saturation: 0.0,
+                ..Adjustments::default()
+            },
+            image.clone(),
+        );
+        // Luminance of 0.299 * 200 + 0.701 * 100
+        assert_eq!(desaturated.get_pixel(0, 0).data, [130, 130, 130, 255]);
+
+        let saturated = apply(
+            Adjustments {
+                saturation: 2.0,
+                ..Adjustments::default()
+            },
+            image,
+        );
+        assert_eq!(saturated.get_pixel(0, 0).data, [255, 70, 70, 255]);
+    }
+
+    #[test]
+    fn rejects_values_out_of_range() {
+        assert!(Adjustments::default().validate().is_ok());
+        for adjustments in [
+            Adjustments {
+                gamma: 0.0,
+                ..Adjustments::default()
+            },
+            Adjustments {
+                gamma: f32::INFINITY,
+                ..Adjustments::default()
+            },
+            Adjustments {
+                saturation: f32::NAN,
+                ..Adjustments::default()
+            },
+            Adjustments {
+                saturation: -1.0,
+                ..Adjustments::default()
+            },
+            Adjustments {
+                sharpen: 1e30,
+                ..Adjustments::default()
+            },
+        ] {
+            assert!(adjustments.validate().is_err(), "{:?}", adjustments);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+/// Shortest match, which is encoded as a back reference
+const LZSS_MIN_MATCH: usize = 3;
+/// Longest match, which still fits into the length byte of a back reference
+const LZSS_MAX_MATCH: usize = LZSS_MIN_MATCH + u8::MAX as usize;
+/// Largest distance of a back reference, which fits into 16 bits
+const LZSS_MAX_DISTANCE: usize = u16::MAX as usize;
+/// Number of bits used to hash the next three bytes while searching matches
+const LZSS_HASH_BITS: u32 = 15;
+/// Number of earlier positions checked for the longest match. Limits the time
+/// needed for data with many short matches, like dithered noise.
+const LZSS_MAX_CHAIN: usize = 64;
+
+/// Encodings the frame sent to the display is available in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lzss,
+}
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Lzss => "lzss",
+        }
+    }
+
+    /// Compress the data and return it together with the compression actually
+    /// used. Falls back to the uncompressed data, if it would grow otherwise,
+    /// like for the noise of dithered 1 bit images.
+    pub fn compress(&self, data: &[u8]) -> (Compression, Vec<u8>) {
+        let compressed = match self {
+            Compression::None => return (Compression::None, data.to_vec()),
+            Compression::Lzss => compress_lzss(data),
+        };
+
+        if compressed.len() >= data.len() {
+            return (Compression::None, data.to_vec());
+        }
+        (*self, compressed)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "none" => Ok(Compression::None),
+            "lzss" => Ok(Compression::Lzss),
+            _ => Err(anyhow!("Unknown compression: {}", name)),
+        }
+    }
+}
+
+/// Chains of earlier positions, which start with the same three bytes
+struct MatchFinder<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    previous: Vec<usize>,
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            head: vec![usize::MAX; 1 << LZSS_HASH_BITS],
+            previous: vec![usize::MAX; data.len()],
+        }
+    }
+
+    #[inline(always)]
+    fn hash(&self, position: usize) -> usize {
+        let bytes = &self.data[position..position + LZSS_MIN_MATCH];
+        let key = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+        (key.wrapping_mul(2654435761) >> (32 - LZSS_HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, position: usize) {
+        if position + LZSS_MIN_MATCH > self.data.len() {
+            return;
+        }
+        let hash = self.hash(position);
+        self.previous[position] = self.head[hash];
+        self.head[hash] = position;
+    }
+
+    /// Distance and length of the longest earlier match of the data at the
+    /// given position
+    fn longest_match(&self, position: usize) -> (usize, usize) {
+        let max_length = LZSS_MAX_MATCH.min(self.data.len() - position);
+        if max_length < LZSS_MIN_MATCH {
+            return (0, 0);
+        }
+
+        let mut best = (0, 0);
+        let mut candidate = self.head[self.hash(position)];
+        for _ in 0..LZSS_MAX_CHAIN {
+            if candidate == usize::MAX || position - candidate > LZSS_MAX_DISTANCE {
+                break;
+            }
+
+            let length = self.data[candidate..]
+                .iter()
+                .zip(&self.data[position..position + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.1 {
+                best = (position - candidate, length);
+                if length == max_length {
+                    break;
+                }
+            }
+
+            candidate = self.previous[candidate];
+        }
+
+        best
+    }
+}
+
+/// Compress the data using a simple LZSS variant, which is cheap to decode on
+/// the ESP32.
+///
+/// The output is a sequence of groups, each starting with a flag byte followed
+/// by up to eight items. Starting with the least significant bit, a cleared
+/// flag marks a literal byte, a set flag a back reference of three bytes: The
+/// distance to the start of the match within the already decoded data as 16
+/// bit little endian, followed by the length of the match minus three.
+pub fn compress_lzss(data: &[u8]) -> Vec<u8> {
+    let mut out_bytes = Vec::with_capacity(data.len() / 2);
+    let mut match_finder = MatchFinder::new(data);
+
+    let mut flags_position = 0;
+    let mut flag = 8;
+    let mut position = 0;
+    while position < data.len() {
+        if flag == 8 {
+            flags_position = out_bytes.len();
+            out_bytes.push(0x0);
+            flag = 0;
+        }
+
+        let (distance, mut length) = match_finder.longest_match(position);
+        if length >= LZSS_MIN_MATCH {
+            out_bytes[flags_position] |= 1 << flag;
+            out_bytes.extend_from_slice(&(distance as u16).to_le_bytes());
+            out_bytes.push((length - LZSS_MIN_MATCH) as u8);
+        } else {
+            out_bytes.push(data[position]);
+            length = 1;
+        }
+
+        for matched in position..position + length {
+            match_finder.insert(matched);
+        }
+        position += length;
+        flag += 1;
+    }
+
+    out_bytes
+}
+
+/// Reference decoder of the data produced by `compress_lzss`, which works
+/// just like the one of the firmware.
+#[cfg(test)]
+fn decompress_lzss(data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut out_bytes = Vec::with_capacity(data.len() * 2);
+
+    let mut position = 0;
+    while position < data.len() {
+        let flags = data[position];
+        position += 1;
+
+        for flag in 0..8 {
+            if position == data.len() {
+                break;
+            }
+
+            if flags & (1 << flag) == 0 {
+                out_bytes.push(data[position]);
+                position += 1;
+                continue;
+            }
+
+            let reference = data
+                .get(position..position + 3)
+                .ok_or_else(|| anyhow!("Truncated back reference at {}", position))?;
+            let distance = u16::from_le_bytes([reference[0], reference[1]]) as usize;
+            let length = reference[2] as usize + LZSS_MIN_MATCH;
+            position += 3;
+
+            if distance == 0 || distance > out_bytes.len() {
+                return Err(anyhow!(
+                    "Invalid back reference distance {} at {}",
+                    distance,
+                    out_bytes.len()
+                ));
+            }
+            // Matches may overlap the data they produce, therefore they are
+            // copied byte by byte
+            let start = out_bytes.len() - distance;
+            for index in start..start + length {
+                out_bytes.push(out_bytes[index]);
+            }
+        }
+    }
+
+    Ok(out_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{DynamicImage, ImageBuffer, Rgba};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::display::{DisplayProfile, Orientation};
+    use crate::dithering::{ColorMetric, DitheringAlgorithm};
+    use crate::image_data;
+
+    fn assert_round_trip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress_lzss(data);
+        assert_eq!(decompress_lzss(&compressed).unwrap(), data);
+        compressed
+    }
+
+    /// Frame as sent to the display, for a colorful gradient
+    fn inkplate_frame(display: DisplayProfile, algorithm: DitheringAlgorithm) -> Vec<u8> {
+        let (width, height) = display.image_dimensions();
+        let image = ImageBuffer::from_fn(width, height, |x, y| {
+            Rgba([
+                (x * 255 / width) as u8,
+                (y * 255 / height) as u8,
+                ((x + y) * 127 / (width + height)) as u8,
+                255,
+            ])
+        });
+        let dithered = image_data::image_dithered(
+            &DynamicImage::ImageRgba8(image),
+            algorithm,
+            display.default_palette(),
+            ColorMetric::Euclidean,
+            false,
+        );
+        let rotated = match display.orientation() {
+            Orientation::Landscape => dithered,
+            Orientation::Portrait => image_data::rotate_image(&dithered),
+        };
+        image_data::inkplate_raw(&rotated, display.default_palette(), display.packing())
+    }
+
+    #[test]
+    fn round_trips_edge_cases() {
+        assert!(assert_round_trip(&[]).is_empty());
+        assert_round_trip(&[0x42]);
+        assert_round_trip(&[0x42, 0x42]);
+        assert_round_trip(&[0x42; LZSS_MAX_MATCH + 1]);
+        assert_round_trip(&[0x42; 100_000]);
+        assert_round_trip(&[0x1, 0x2, 0x3, 0x1, 0x2, 0x3, 0x1, 0x2]);
+    }
+
+    #[test]
+    fn round_trips_random_data() {
+        let mut rng = StdRng::seed_from_u64(42);
+        for length in [7, 8, 9, 1000, 70_000] {
+            // Few distinct values result in many short matches
+            let data: Vec<u8> = (0..length).map(|_| rng.gen_range(0..4)).collect();
+            assert_round_trip(&data);
+            let data: Vec<u8> = (0..length).map(|_| rng.gen()).collect();
+            assert_round_trip(&data);
+        }
+    }
+
+    #[test]
+    fn round_trips_inkplate_frames() {
+        for display in [
+            DisplayProfile::Inkplate6Color,
+            DisplayProfile::Inkplate6Monochrome,
+            DisplayProfile::Inkplate2,
+        ] {
+            for algorithm in [
+                DitheringAlgorithm::JarvisJudiceNinke,
+                DitheringAlgorithm::Bayer4,
+                DitheringAlgorithm::None,
+            ] {
+                let frame = inkplate_frame(display, algorithm);
+                let compressed = assert_round_trip(&frame);
+
+                // Error diffusion on the 7 color panel still leaves runs of
+                // equal nibbles, only the 1 bit noise is incompressible
+                if display == DisplayProfile::Inkplate6Color {
+                    assert!(
+                        compressed.len() < frame.len() / 2,
+                        "{:?}: {} bytes compressed to {}",
+                        algorithm,
+                        frame.len(),
+                        compressed.len()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn falls_back_to_uncompressed_data() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let noise: Vec<u8> = (0..1000).map(|_| rng.gen()).collect();
+        assert_eq!(
+            Compression::Lzss.compress(&noise),
+            (Compression::None, noise.clone())
+        );
+
+        let flat = [0x11; 1000];
+        let (compression, compressed) = Compression::Lzss.compress(&flat);
+        assert_eq!(compression, Compression::Lzss);
+        assert_eq!(decompress_lzss(&compressed).unwrap(), flat);
+    }
+
+    #[test]
+    fn rejects_invalid_back_references() {
+        // Reference before the start of the data
+        assert!(decompress_lzss(&[0b10, 0x42, 0x02, 0x00, 0x00]).is_err());
+        // Distance of zero
+        assert!(decompress_lzss(&[0b10, 0x42, 0x00, 0x00, 0x00]).is_err());
+        // Truncated reference
+        assert!(decompress_lzss(&[0b10, 0x42, 0x01]).is_err());
+    }
+
+    #[test]
+    fn parses_compression_names() {
+        assert_eq!("none".parse::<Compression>().unwrap(), Compression::None);
+        assert_eq!("lzss".parse::<Compression>().unwrap(), Compression::Lzss);
+        assert!("gzip".parse::<Compression>().is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use regex::{Regex, RegexBuilder};
+use rusqlite::{params, Connection};
+use serde_json::Value;
+
+use crate::image_source::ImageSource;
+use crate::lexica::LazyLexicaImage;
+use crate::ImageRequest;
+
+/// Score above which numeric nsfw fields mark an image as not safe for work
+const NSFW_SCORE_THRESHOLD: f64 = 0.5;
+
+/// Rejects candidates, whose prompt matches any of the configured blocklist
+/// patterns, or which are marked as nsfw in their raw documents.
+pub struct ContentFilter {
+    blocklist: Vec<Regex>,
+    allow_nsfw: bool,
+}
+
+/// Check all top level fields of the document named like `nsfw` for a truthy
+/// value or a score above the threshold.
+fn is_marked_nsfw(document: &Value) -> bool {
+    let fields = match document.as_object() {
+        Some(fields) => fields,
+        None => return false,
+    };
+
+    fields
+        .iter()
+        .filter(|(key, _)| key.to_lowercase().contains("nsfw"))
+        .any(|(_, value)| match value {
+            Value::Bool(nsfw) => *nsfw,
+            Value::Number(score) => score.as_f64().unwrap_or(0.0) > NSFW_SCORE_THRESHOLD,
+            Value::String(nsfw) => nsfw.eq_ignore_ascii_case("true"),
+            _ => false,
+        })
+}
+
+fn rejected_ids(connection: &Connection) -> anyhow::Result<HashSet<String>> {
+    let mut statement = connection.prepare("SELECT id FROM rejected_image")?;
+    let ids = statement
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_, _>>()?;
+    Ok(ids)
+}
+
+fn remember_rejected(connection: &Connection, id: &str, reason: &str) -> anyhow::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    connection.execute(
+        "
+        INSERT OR IGNORE INTO rejected_image
+            (id, reason, rejected_at)
+        VALUES
+            (?1, ?2, ?3)
+        ",
+        params![id, reason, now],
+    )?;
+    Ok(())
+}
+
+impl ContentFilter {
+    /// Create a filter from a list of case insensitive regular expressions
+    /// separated by `;`. Plain words are valid expressions as well.
+    pub fn parse(blocklist: &str, allow_nsfw: bool) -> anyhow::Result<Self> {
+        let blocklist = blocklist
+            .split(';')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| RegexBuilder::new(pattern).case_insensitive(true).build())
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            blocklist,
+            allow_nsfw,
+        })
+    }
+
+    fn rejection_reason(&self, lexica_image: &LazyLexicaImage) -> Option<String> {
+        if !self.allow_nsfw
+            && (is_marked_nsfw(&lexica_image.metadata) || is_marked_nsfw(&lexica_image.prompt))
+        {
+            return Some("nsfw".to_string());
+        }
+
+        let prompt = lexica_image.prompt["prompt"].as_str().unwrap_or_default();
+        self.blocklist
+            .iter()
+            .find(|pattern| pattern.is_match(prompt))
+            .map(|pattern| format!("blocklist: {}", pattern.as_str()))
+    }
+
+    /// Remove all candidates, which are rejected by this filter or have been
+    /// rejected before. Newly rejected ones are remembered in the database.
+    pub fn filter(
+        &self,
+        connection: &Connection,
+        candidates: Vec<LazyLexicaImage>,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>> {
+        let rejected = rejected_ids(connection)?;
+
+        let mut accepted = Vec::with_capacity(candidates.len());
+        for candidate in candidates {
+            if rejected.contains(&candidate.id) {
+                continue;
+            }
+
+            match self.rejection_reason(&candidate) {
+                Some(reason) => {
+                    println!("Rejected image {} ({})", candidate.id, reason);
+                    remember_rejected(connection, &candidate.id, &reason)?;
+                }
+                None => accepted.push(candidate),
+            }
+        }
+
+        Ok(accepted)
+    }
+}
+
+/// Applies a content filter to all candidates of the wrapped image source
+pub struct FilteredImageSource {
+    source: Box<dyn ImageSource>,
+    filter: Arc<ContentFilter>,
+}
+
+impl FilteredImageSource {
+    pub fn new(source: Box<dyn ImageSource>, filter: Arc<ContentFilter>) -> Self {
+        Self { source, filter }
+    }
+}
+
+impl ImageSource for FilteredImageSource {
+    fn name(&self) -> &'static str {
+        self.source.name()
+    }
+
+    fn fetch_candidates(
+        &self,
+        connection: &Connection,
+        request: &ImageRequest,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>> {
+        self.source
+            .fetch_filtered_candidates(connection, request, &|candidates| {
+                self.filter.filter(connection, candidates)
+            })
+    }
+}
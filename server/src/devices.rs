@@ -0,0 +1,352 @@
+use std::time::SystemTime;
+
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+
+use crate::adjustments::Adjustments;
+use crate::display::DisplayProfile;
+use crate::dithering::{ColorMetric, DitheringAlgorithm, Palette};
+use crate::overlay::Overlays;
+use crate::{ImageRequest, PersistedConfig};
+
+/// Settings of a single device. Settings, which are not given, are taken from
+/// the persisted config.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DeviceSettings {
+    /// Comma separated list of image sources, like `LEXICA_INKPLATE_IMAGE_SOURCE`
+    pub image_source: Option<String>,
+    pub search: Option<String>,
+    pub display: Option<DisplayProfile>,
+    pub dithering: Option<DitheringAlgorithm>,
+    pub palette: Option<Palette>,
+    pub color_metric: Option<ColorMetric>,
+    pub serpentine: Option<bool>,
+    pub adjustments: Option<Adjustments>,
+    pub update_interval: Option<usize>,
+    pub update_at_night: Option<bool>,
+    pub overlays: Option<Overlays>,
+}
+
+impl DeviceSettings {
+    /// The persisted config with the settings of the device applied
+    pub fn apply(&self, config: &PersistedConfig) -> PersistedConfig {
+        let mut applied = config.clone();
+        if let Some(display) = self.display {
+            applied.display = display;
+            // The configured palette might not be supported by this display
+            if display.check_palette(applied.palette).is_err() {
+                applied.palette = display.default_palette();
+            }
+        }
+        applied.dithering = self.dithering.unwrap_or(applied.dithering);
+        applied.palette = self.palette.unwrap_or(applied.palette);
+        applied.color_metric = self.color_metric.unwrap_or(applied.color_metric);
+        applied.serpentine = self.serpentine.unwrap_or(applied.serpentine);
+        applied.adjustments = self.adjustments.unwrap_or(applied.adjustments);
+        applied.update_interval = self.update_interval.unwrap_or(applied.update_interval);
+        applied.update_at_night = self.update_at_night.unwrap_or(applied.update_at_night);
+        if let Some(overlays) = &self.overlays {
+            applied.overlays = overlays.clone();
+        }
+        applied
+    }
+}
+
+/// A display, which has requested frames, identified by a token or its MAC
+/// address
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Device {
+    pub id: String,
+    pub name: String,
+    pub settings: DeviceSettings,
+    /// Image currently shown on the device
+    pub current_image: Option<String>,
+    pub registered_at: u64,
+    pub last_seen_at: u64,
+}
+
+impl Device {
+    /// The persisted config with the settings of this device applied
+    pub fn config(&self, config: &PersistedConfig) -> PersistedConfig {
+        self.settings.apply(config)
+    }
+
+    /// Request used for this device, if no parameters are given by it
+    pub fn default_request(&self, config: &PersistedConfig) -> ImageRequest {
+        ImageRequest {
+            device: Some(self.id.clone()),
+            image_source: self.settings.image_source.clone(),
+            search: self.settings.search.clone(),
+            ..self.config(config).default_request()
+        }
+    }
+}
+
+/// Name and settings of a device, as changed by the client
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceUpdate {
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub settings: DeviceSettings,
+}
+
+/// Image shown on a device
+#[derive(Debug, Clone, Serialize)]
+pub struct ShownImage {
+    pub lexica_image: String,
+    pub prompt: Option<String>,
+    pub shown_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn device_from_row(row: &Row) -> rusqlite::Result<(Device, String)> {
+    Ok((
+        Device {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            settings: DeviceSettings::default(),
+            current_image: row.get(3)?,
+            registered_at: row.get(4)?,
+            last_seen_at: row.get(5)?,
+        },
+        row.get(2)?,
+    ))
+}
+
+fn with_settings((mut device, settings): (Device, String)) -> anyhow::Result<Device> {
+    device.settings = serde_json::from_str(&settings)?;
+    Ok(device)
+}
+
+pub fn load_device(connection: &Connection, id: &str) -> anyhow::Result<Option<Device>> {
+    connection
+        .query_row(
+            "
+            SELECT id, name, settings, current_image, registered_at, last_seen_at
+            FROM device
+            WHERE id = ?1
+            ",
+            [id],
+            device_from_row,
+        )
+        .optional()?
+        .map(with_settings)
+        .transpose()
+}
+
+pub fn list_devices(connection: &Connection) -> anyhow::Result<Vec<Device>> {
+    let mut statement = connection.prepare(
+        "
+        SELECT id, name, settings, current_image, registered_at, last_seen_at
+        FROM device
+        ORDER BY registered_at, id
+        ",
+    )?;
+    let devices = statement
+        .query_map([], device_from_row)?
+        .collect::<Result<Vec<_>, _>>()?;
+    devices.into_iter().map(with_settings).collect()
+}
+
+/// Register the device, if it has not been seen before, and remember when it
+/// has last been seen
+pub fn register_device(connection: &Connection, id: &str) -> anyhow::Result<Device> {
+    let now = now();
+    connection.execute(
+        "
+        INSERT INTO device
+            (id, registered_at, last_seen_at)
+        VALUES
+            (?1, ?2, ?2)
+        ON CONFLICT (id) DO UPDATE SET last_seen_at = ?2
+        ",
+        params![id, now],
+    )?;
+
+    Ok(load_device(connection, id)?.unwrap())
+}
+
+/// Change the name and settings of the device, which is registered if needed
+pub fn update_device(
+    connection: &Connection,
+    id: &str,
+    update: &DeviceUpdate,
+) -> anyhow::Result<Device> {
+    connection.execute(
+        "
+        INSERT INTO device
+            (id, name, settings, registered_at, last_seen_at)
+        VALUES
+            (?1, ?2, ?3, ?4, ?4)
+        ON CONFLICT (id) DO UPDATE SET name = ?2, settings = ?3
+        ",
+        params![
+            id,
+            update.name,
+            serde_json::to_string(&update.settings)?,
+            now()
+        ],
+    )?;
+
+    Ok(load_device(connection, id)?.unwrap())
+}
+
+/// Remove the device from the registry. Its history is kept.
+pub fn remove_device(connection: &Connection, id: &str) -> anyhow::Result<bool> {
+    Ok(connection.execute("DELETE FROM device WHERE id = ?1", [id])? > 0)
+}
+
+/// Images most recently shown on the device
+pub fn device_history(
+    connection: &Connection,
+    id: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<ShownImage>> {
+    let mut statement = connection.prepare(
+        "
+        SELECT s.lexica_image, p.prompt, s.shown_at
+        FROM posterity s
+        LEFT JOIN lexica_image i ON i.id = s.lexica_image
+        LEFT JOIN lexica_prompt p ON p.id = i.prompt
+        WHERE s.device = ?1
+        ORDER BY s.shown_at DESC, s.id DESC
+        LIMIT ?2
+        ",
+    )?;
+    let history = statement
+        .query_map(params![id, limit], |row| {
+            Ok(ShownImage {
+                lexica_image: row.get(0)?,
+                prompt: row.get(1)?,
+                shown_at: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::posterity::create_posterity_db;
+
+    fn test_db() -> Connection {
+        let mut connection = Connection::open_in_memory().unwrap();
+        create_posterity_db(&mut connection);
+        connection
+    }
+
+    fn show_image(connection: &Connection, image: &str, device: Option<&str>, shown_at: u64) {
+        connection
+            .execute(
+                "
+                INSERT INTO posterity
+                    (lexica_image, cropped_image, dithered_image, device, shown_at)
+                VALUES
+                    (?1, x'', x'', ?2, ?3)
+                ",
+                params![image, device, shown_at],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn registers_devices_once() {
+        let connection = test_db();
+        assert!(load_device(&connection, "kitchen").unwrap().is_none());
+
+        let device = register_device(&connection, "kitchen").unwrap();
+        assert_eq!(device.id, "kitchen");
+        assert_eq!(device.name, "");
+        assert_eq!(device.settings, DeviceSettings::default());
+        assert_eq!(device.registered_at, device.last_seen_at);
+
+        register_device(&connection, "kitchen").unwrap();
+        register_device(&connection, "hallway").unwrap();
+        let ids: Vec<String> = list_devices(&connection)
+            .unwrap()
+            .into_iter()
+            .map(|device| device.id)
+            .collect();
+        assert_eq!(ids.len(), 2);
+        assert!(ids.contains(&"kitchen".to_string()));
+
+        assert!(remove_device(&connection, "kitchen").unwrap());
+        assert!(!remove_device(&connection, "kitchen").unwrap());
+        assert!(load_device(&connection, "kitchen").unwrap().is_none());
+    }
+
+    #[test]
+    fn keeps_settings_when_seen_again() {
+        let connection = test_db();
+        let update = DeviceUpdate {
+            name: "Kitchen".to_string(),
+            settings: DeviceSettings {
+                search: Some("cats".to_string()),
+                update_interval: Some(60),
+                ..DeviceSettings::default()
+            },
+        };
+        let device = update_device(&connection, "kitchen", &update).unwrap();
+        assert_eq!(device.name, "Kitchen");
+        assert_eq!(device.settings, update.settings);
+
+        let device = register_device(&connection, "kitchen").unwrap();
+        assert_eq!(device.name, "Kitchen");
+        assert_eq!(device.settings, update.settings);
+        assert_eq!(
+            device.config(&PersistedConfig::default()).update_interval,
+            60
+        );
+    }
+
+    #[test]
+    fn lists_history_of_device_only() {
+        let connection = test_db();
+        connection
+            .execute(
+                "INSERT INTO lexica_prompt (id, prompt, raw_document) VALUES ('p', 'Cats', '{}')",
+                [],
+            )
+            .unwrap();
+        connection
+            .execute(
+                "
+                INSERT INTO lexica_image (id, prompt, url, raw_document, image)
+                VALUES ('first', 'p', '', '{}', x'')
+                ",
+                [],
+            )
+            .unwrap();
+        show_image(&connection, "first", Some("kitchen"), 100);
+        show_image(&connection, "second", Some("hallway"), 200);
+        show_image(&connection, "third", Some("kitchen"), 300);
+        show_image(&connection, "fourth", None, 400);
+
+        let history = device_history(&connection, "kitchen", 10).unwrap();
+        let shown: Vec<(&str, Option<&str>, u64)> = history
+            .iter()
+            .map(|image| {
+                (
+                    image.lexica_image.as_str(),
+                    image.prompt.as_deref(),
+                    image.shown_at,
+                )
+            })
+            .collect();
+        assert_eq!(
+            shown,
+            vec![("third", None, 300), ("first", Some("Cats"), 100)]
+        );
+
+        assert_eq!(device_history(&connection, "kitchen", 1).unwrap().len(), 1);
+    }
+}
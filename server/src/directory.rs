@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use rusqlite::{params, Connection};
+use serde_json::json;
+
+use crate::image_source::ImageSource;
+use crate::lexica::LazyLexicaImage;
+use crate::ImageRequest;
+
+const SUPPORTED_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+
+/// Prefix of all image ids provided by the directory source. It is used to
+/// separate them from lexica images within the posterity database.
+const ID_PREFIX: &str = "directory:";
+
+/// Whether the image id has been provided by the directory source
+pub fn is_directory_image(id: &str) -> bool {
+    id.starts_with(ID_PREFIX)
+}
+
+fn is_supported_image(path: &Path) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => SUPPORTED_EXTENSIONS.contains(&extension.to_lowercase().as_str()),
+        None => false,
+    }
+}
+
+fn collect_images(directory: &Path, images: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        // Symlinked directories are not followed, as they might form a loop
+        if entry.file_type()?.is_dir() {
+            collect_images(&path, images)?;
+        } else if path.is_file() && is_supported_image(&path) {
+            images.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Number of times each image of this source has been shown already on the
+/// device, or on any device if none is given.
+fn shown_counts(
+    connection: &Connection,
+    device: Option<&str>,
+) -> anyhow::Result<HashMap<String, usize>> {
+    let mut statement = connection.prepare(
+        "
+        SELECT lexica_image, COUNT(id)
+        FROM posterity
+        WHERE lexica_image LIKE ?1 AND (?2 IS NULL OR device = ?2)
+        GROUP BY lexica_image
+        ",
+    )?;
+    let rows = statement.query_map(params![format!("{}%", ID_PREFIX), device], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, usize>(1)?))
+    })?;
+
+    let mut counts = HashMap::new();
+    for row in rows {
+        let (id, count) = row?;
+        counts.insert(id, count);
+    }
+    Ok(counts)
+}
+
+fn image_id(directory: &Path, path: &Path) -> String {
+    let relative_path = path.strip_prefix(directory).unwrap_or(path);
+    format!("{}{}", ID_PREFIX, relative_path.display())
+}
+
+fn lazy_directory_image(directory: &Path, path: PathBuf) -> LazyLexicaImage {
+    let id = image_id(directory, &path);
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    LazyLexicaImage::with_loader(
+        id.clone(),
+        format!("file://{}", path.display()),
+        json!({ "id": id, "prompt": name }),
+        json!({ "id": id, "path": path.display().to_string() }),
+        Box::new(move || Ok(image::open(&path)?)),
+    )
+}
+
+/// Images (JPEG or PNG) read recursively from a local directory.
+///
+/// Only images which have been shown the least amount of times on the
+/// requesting device are provided as candidates. Therefore no image is
+/// repeated, before every image within the directory has been shown on it.
+pub struct DirectoryImageSource {
+    directory: PathBuf,
+}
+
+impl DirectoryImageSource {
+    pub fn new(directory: PathBuf) -> Self {
+        Self { directory }
+    }
+}
+
+impl ImageSource for DirectoryImageSource {
+    fn name(&self) -> &'static str {
+        "directory"
+    }
+
+    fn fetch_candidates(
+        &self,
+        connection: &Connection,
+        request: &ImageRequest,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>> {
+        let mut images = Vec::new();
+        collect_images(&self.directory, &mut images)?;
+
+        let counts = shown_counts(connection, request.device.as_deref())?;
+        let shown_count = |path: &PathBuf| -> usize {
+            let id = image_id(&self.directory, path);
+            counts.get(&id).copied().unwrap_or(0)
+        };
+
+        let least_shown = images.iter().map(shown_count).min().unwrap_or(0);
+        let candidates = images
+            .into_iter()
+            .filter(|path| shown_count(path) == least_shown)
+            .map(|path| lazy_directory_image(&self.directory, path))
+            .collect();
+
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn skips_symlinked_directories() {
+        let directory =
+            std::env::temp_dir().join(format!("directory-source-{}", std::process::id()));
+        let subdirectory = directory.join("album");
+        std::fs::create_dir_all(&subdirectory).unwrap();
+        std::fs::write(directory.join("first.jpg"), []).unwrap();
+        std::fs::write(subdirectory.join("second.PNG"), []).unwrap();
+        std::fs::write(subdirectory.join("notes.txt"), []).unwrap();
+        std::os::unix::fs::symlink(&directory, subdirectory.join("loop")).unwrap();
+
+        let mut images = Vec::new();
+        let collected = collect_images(&directory, &mut images);
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        collected.unwrap();
+        images.sort();
+        assert_eq!(
+            images,
+            vec![
+                directory.join("album/second.PNG"),
+                directory.join("first.jpg")
+            ]
+        );
+    }
+}
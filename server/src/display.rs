@@ -0,0 +1,158 @@
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::dithering::Palette;
+
+/// How the panel is mounted. Portrait images are rotated by 90 degrees before
+/// they are sent to the display.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Landscape,
+    Portrait,
+}
+
+/// How the palette indices of the pixels are packed into bytes.
+///
+/// Pixels are packed from the most significant bits on, and each row starts
+/// with a new byte. The last byte of a row is padded if necessary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packing {
+    /// Two pixels per byte, each index shifted into the upper three bits of
+    /// its nibble, as expected by the firmware of the Inkplate 6COLOR
+    ShiftedNibbles,
+    /// Two pixels per byte
+    Nibbles,
+    /// Four pixels per byte
+    TwoBits,
+    /// Eight pixels per byte
+    Bits,
+}
+
+impl Packing {
+    pub fn bits_per_pixel(&self) -> u32 {
+        match self {
+            Packing::ShiftedNibbles | Packing::Nibbles => 4,
+            Packing::TwoBits => 2,
+            Packing::Bits => 1,
+        }
+    }
+
+    /// Value stored for the given palette index
+    pub fn value(&self, index: u8) -> u8 {
+        match self {
+            Packing::ShiftedNibbles => index << 1,
+            _ => index,
+        }
+    }
+
+    /// Number of bytes a row of the given width is packed into
+    pub fn row_bytes(&self, width: u32) -> usize {
+        let pixels_per_byte = 8 / self.bits_per_pixel();
+        width.div_ceil(pixels_per_byte) as usize
+    }
+}
+
+/// Supported displays, which can be selected by name. A profile defines the
+/// resolution and orientation of the panel, the palettes it is able to show
+/// and how the image is encoded for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisplayProfile {
+    /// 7 color ACeP panel
+    #[default]
+    Inkplate6Color,
+    /// 3 bit grayscale mode of the Inkplate 6
+    Inkplate6,
+    /// 1 bit black and white mode of the Inkplate 6
+    Inkplate6Monochrome,
+    /// 3 bit grayscale mode of the Inkplate 10
+    Inkplate10,
+    /// 1 bit black and white mode of the Inkplate 10
+    Inkplate10Monochrome,
+    /// Black, white and red panel
+    Inkplate2,
+}
+
+impl DisplayProfile {
+    /// Resolution of the panel as width and height in its native orientation
+    pub fn resolution(&self) -> (u32, u32) {
+        match self {
+            DisplayProfile::Inkplate6Color => (600, 448),
+            DisplayProfile::Inkplate6 | DisplayProfile::Inkplate6Monochrome => (800, 600),
+            DisplayProfile::Inkplate10 | DisplayProfile::Inkplate10Monochrome => (1200, 825),
+            DisplayProfile::Inkplate2 => (212, 104),
+        }
+    }
+
+    pub fn orientation(&self) -> Orientation {
+        match self {
+            DisplayProfile::Inkplate2 => Orientation::Landscape,
+            _ => Orientation::Portrait,
+        }
+    }
+
+    /// Dimensions of the image shown, which are swapped for portrait panels
+    pub fn image_dimensions(&self) -> (u32, u32) {
+        let (width, height) = self.resolution();
+        match self.orientation() {
+            Orientation::Landscape => (width, height),
+            Orientation::Portrait => (height, width),
+        }
+    }
+
+    /// Palettes the display is able to show. The first one is the default.
+    pub fn palettes(&self) -> &'static [Palette] {
+        match self {
+            DisplayProfile::Inkplate6Color => &[Palette::AcepCalibrated, Palette::Acep],
+            DisplayProfile::Inkplate6 | DisplayProfile::Inkplate10 => &[Palette::Grayscale],
+            DisplayProfile::Inkplate6Monochrome | DisplayProfile::Inkplate10Monochrome => {
+                &[Palette::Monochrome]
+            }
+            DisplayProfile::Inkplate2 => &[Palette::BlackWhiteRed],
+        }
+    }
+
+    pub fn default_palette(&self) -> Palette {
+        self.palettes()[0]
+    }
+
+    pub fn check_palette(&self, palette: Palette) -> anyhow::Result<()> {
+        if !self.palettes().contains(&palette) {
+            return Err(anyhow!(
+                "Palette {:?} is not supported by display {:?}",
+                palette,
+                self
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn packing(&self) -> Packing {
+        match self {
+            DisplayProfile::Inkplate6Color => Packing::ShiftedNibbles,
+            DisplayProfile::Inkplate6 | DisplayProfile::Inkplate10 => Packing::Nibbles,
+            DisplayProfile::Inkplate6Monochrome | DisplayProfile::Inkplate10Monochrome => {
+                Packing::Bits
+            }
+            DisplayProfile::Inkplate2 => Packing::TwoBits,
+        }
+    }
+}
+
+impl FromStr for DisplayProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "inkplate6_color" => Ok(DisplayProfile::Inkplate6Color),
+            "inkplate6" => Ok(DisplayProfile::Inkplate6),
+            "inkplate6_monochrome" => Ok(DisplayProfile::Inkplate6Monochrome),
+            "inkplate10" => Ok(DisplayProfile::Inkplate10),
+            "inkplate10_monochrome" => Ok(DisplayProfile::Inkplate10Monochrome),
+            "inkplate2" => Ok(DisplayProfile::Inkplate2),
+            _ => Err(anyhow!("Unknown display: {}", name)),
+        }
+    }
+}
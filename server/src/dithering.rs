@@ -1,4 +1,13 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::thread;
+
+use anyhow::anyhow;
 use image::ImageBuffer;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
 
 type Kernel5x5 = [[u32; 5]; 5];
 #[derive(Debug, Copy, Clone)]
@@ -24,7 +33,6 @@ impl Dithering {
     }
 }
 
-#[allow(dead_code)]
 #[inline(always)]
 pub fn jarvis_judice_ninke() -> Dithering {
     Dithering::new([
@@ -36,7 +44,6 @@ pub fn jarvis_judice_ninke() -> Dithering {
     ])
 }
 
-#[allow(dead_code)]
 #[inline(always)]
 pub fn floyd_steinberg() -> Dithering {
     Dithering::new([
@@ -48,7 +55,6 @@ pub fn floyd_steinberg() -> Dithering {
     ])
 }
 
-#[allow(dead_code)]
 #[inline(always)]
 pub fn atkinson() -> Dithering {
     Dithering::new([
@@ -60,7 +66,61 @@ pub fn atkinson() -> Dithering {
     ])
 }
 
-#[allow(dead_code)]
+#[inline(always)]
+pub fn stucki() -> Dithering {
+    Dithering::new([
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 8, 4],
+        [2, 4, 8, 4, 2],
+        [1, 2, 4, 2, 1],
+    ])
+}
+
+#[inline(always)]
+pub fn burkes() -> Dithering {
+    Dithering::new([
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 8, 4],
+        [2, 4, 8, 4, 2],
+        [0, 0, 0, 0, 0],
+    ])
+}
+
+#[inline(always)]
+pub fn sierra3() -> Dithering {
+    Dithering::new([
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 5, 3],
+        [2, 4, 5, 4, 2],
+        [0, 2, 3, 2, 0],
+    ])
+}
+
+#[inline(always)]
+pub fn sierra2() -> Dithering {
+    Dithering::new([
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 4, 3],
+        [1, 2, 3, 2, 1],
+        [0, 0, 0, 0, 0],
+    ])
+}
+
+#[inline(always)]
+pub fn sierra_lite() -> Dithering {
+    Dithering::new([
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 0, 0],
+        [0, 0, 0, 2, 0],
+        [0, 1, 1, 0, 0],
+        [0, 0, 0, 0, 0],
+    ])
+}
+
 #[inline(always)]
 pub fn none() -> Dithering {
     Dithering::new([
@@ -72,6 +132,142 @@ pub fn none() -> Dithering {
     ])
 }
 
+/// Dithering algorithms, which can be selected by name. Either error
+/// diffusion using one of the kernels, or ordered dithering using one of the
+/// threshold maps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DitheringAlgorithm {
+    #[default]
+    JarvisJudiceNinke,
+    FloydSteinberg,
+    Atkinson,
+    Stucki,
+    Burkes,
+    Sierra3,
+    Sierra2,
+    SierraLite,
+    None,
+    Bayer2,
+    Bayer4,
+    Bayer8,
+    BlueNoise,
+}
+
+impl DitheringAlgorithm {
+    /// Error diffusion kernel of the algorithm, if it is one
+    pub fn kernel(&self) -> Option<Dithering> {
+        match self {
+            DitheringAlgorithm::JarvisJudiceNinke => Some(jarvis_judice_ninke()),
+            DitheringAlgorithm::FloydSteinberg => Some(floyd_steinberg()),
+            DitheringAlgorithm::Atkinson => Some(atkinson()),
+            DitheringAlgorithm::Stucki => Some(stucki()),
+            DitheringAlgorithm::Burkes => Some(burkes()),
+            DitheringAlgorithm::Sierra3 => Some(sierra3()),
+            DitheringAlgorithm::Sierra2 => Some(sierra2()),
+            DitheringAlgorithm::SierraLite => Some(sierra_lite()),
+            DitheringAlgorithm::None => Some(none()),
+            _ => None,
+        }
+    }
+
+    /// Dither the image. Serpentine scanning only affects error diffusion.
+    pub fn apply(
+        &self,
+        image: ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+        palette: Vec<u32>,
+        metric: ColorMetric,
+        serpentine: bool,
+    ) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+        if let Some(kernel) = self.kernel() {
+            return apply_error_diffusion(image, kernel, palette, metric, serpentine);
+        }
+
+        match self {
+            DitheringAlgorithm::Bayer2 => {
+                apply_ordered_dithering(image, &bayer(2), palette, metric)
+            }
+            DitheringAlgorithm::Bayer4 => {
+                apply_ordered_dithering(image, &bayer(4), palette, metric)
+            }
+            DitheringAlgorithm::Bayer8 => {
+                apply_ordered_dithering(image, &bayer(8), palette, metric)
+            }
+            DitheringAlgorithm::BlueNoise => {
+                apply_ordered_dithering(image, blue_noise(), palette, metric)
+            }
+            _ => unreachable!("{:?} is an error diffusion kernel", self),
+        }
+    }
+}
+
+impl FromStr for DitheringAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "jarvis_judice_ninke" | "jjn" => Ok(DitheringAlgorithm::JarvisJudiceNinke),
+            "floyd_steinberg" => Ok(DitheringAlgorithm::FloydSteinberg),
+            "atkinson" => Ok(DitheringAlgorithm::Atkinson),
+            "stucki" => Ok(DitheringAlgorithm::Stucki),
+            "burkes" => Ok(DitheringAlgorithm::Burkes),
+            "sierra3" => Ok(DitheringAlgorithm::Sierra3),
+            "sierra2" => Ok(DitheringAlgorithm::Sierra2),
+            "sierra_lite" => Ok(DitheringAlgorithm::SierraLite),
+            "none" => Ok(DitheringAlgorithm::None),
+            "bayer2" => Ok(DitheringAlgorithm::Bayer2),
+            "bayer4" => Ok(DitheringAlgorithm::Bayer4),
+            "bayer8" => Ok(DitheringAlgorithm::Bayer8),
+            "blue_noise" => Ok(DitheringAlgorithm::BlueNoise),
+            _ => Err(anyhow!("Unknown dithering algorithm: {}", name)),
+        }
+    }
+}
+
+/// Color palettes, which can be selected by name. The position of each color
+/// within the palette is the index used by the display for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Palette {
+    /// The nominal colors of the ACeP panel
+    Acep,
+    /// The colors the ACeP panel is actually able to show
+    #[default]
+    AcepCalibrated,
+    Grayscale,
+    /// Black and white of 1 bit panels
+    Monochrome,
+    /// The three colors of the Inkplate 2
+    BlackWhiteRed,
+}
+
+impl Palette {
+    pub fn colors(&self) -> Vec<u32> {
+        match self {
+            Palette::Acep => palette_7_acep(),
+            Palette::AcepCalibrated => palette_7_acep_calibrated(),
+            Palette::Grayscale => palette_8_grayscale(),
+            Palette::Monochrome => palette_2_monochrome(),
+            Palette::BlackWhiteRed => palette_3_black_white_red(),
+        }
+    }
+}
+
+impl FromStr for Palette {
+    type Err = anyhow::Error;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "acep" => Ok(Palette::Acep),
+            "acep_calibrated" => Ok(Palette::AcepCalibrated),
+            "grayscale" => Ok(Palette::Grayscale),
+            "monochrome" => Ok(Palette::Monochrome),
+            "black_white_red" => Ok(Palette::BlackWhiteRed),
+            _ => Err(anyhow!("Unknown palette: {}", name)),
+        }
+    }
+}
+
 #[inline(always)]
 pub fn palette_8_grayscale() -> Vec<u32> {
     vec![
@@ -79,6 +275,23 @@ pub fn palette_8_grayscale() -> Vec<u32> {
     ]
 }
 
+#[inline(always)]
+pub fn palette_2_monochrome() -> Vec<u32> {
+    vec![
+        0xFFFFFF, // white
+        0x000000, // black
+    ]
+}
+
+#[inline(always)]
+pub fn palette_3_black_white_red() -> Vec<u32> {
+    vec![
+        0xFFFFFF, // white
+        0x000000, // black
+        0xFF0000, // red
+    ]
+}
+
 #[inline(always)]
 pub fn palette_7_acep() -> Vec<u32> {
     vec![
@@ -92,33 +305,274 @@ pub fn palette_7_acep() -> Vec<u32> {
     ]
 }
 
-fn color_distance(color1: u32, color2: u32) -> f64 {
-    let r1 = (color1 >> 16 & 0xff) as u8;
-    let r2 = (color2 >> 16 & 0xff) as u8;
-    let g1 = (color1 >> 8 & 0xff) as u8;
-    let g2 = (color2 >> 8 & 0xff) as u8;
-    let b1 = (color1 >> 0 & 0xff) as u8;
-    let b2 = (color2 >> 0 & 0xff) as u8;
+/// Colors of the 7 color ACeP inks as measured on a real panel, in the same
+/// order as `palette_7_acep`. The values are the swatches published by
+/// Pimoroni for the Inky Impression, which uses the same panel as the
+/// Inkplate 6COLOR.
+#[inline(always)]
+pub fn palette_7_acep_calibrated() -> Vec<u32> {
+    vec![
+        0x393039, // black
+        0xFFFFFF, // white
+        0x3A5B46, // green
+        0x3D3B5E, // blue
+        0x9C484B, // red
+        0xD0BE47, // yellow
+        0xB16A49, // orange
+    ]
+}
+
+/// Metrics used to find the palette color closest to a pixel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMetric {
+    /// Plain euclidean distance of the RGB values
+    #[default]
+    Euclidean,
+    /// Euclidean RGB distance weighted according to the mean red value
+    Redmean,
+    /// Euclidean distance within the CIELAB color space (ΔE*76)
+    Cie76,
+    /// CIEDE2000 color difference (ΔE*00)
+    Ciede2000,
+}
 
-    let dr = r1 as i64 - r2 as i64;
-    let dg = g1 as i64 - g2 as i64;
-    let db = b1 as i64 - b2 as i64;
+impl FromStr for ColorMetric {
+    type Err = anyhow::Error;
 
-    let distance_squared = (dr * dr + dg * dg + db * db) as f64;
-    distance_squared.sqrt()
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "euclidean" => Ok(ColorMetric::Euclidean),
+            "redmean" => Ok(ColorMetric::Redmean),
+            "cie76" => Ok(ColorMetric::Cie76),
+            "ciede2000" => Ok(ColorMetric::Ciede2000),
+            _ => Err(anyhow!("Unknown color metric: {}", name)),
+        }
+    }
 }
 
-pub fn map_color_to_palette_index(color: u32, palette: &Vec<u32>) -> usize {
-    let mut current_distance = f64::INFINITY;
-    let mut current_index = 0;
-    for i in 0..palette.len() {
-        let distance = color_distance(color, palette[i]);
-        if distance < current_distance {
-            current_distance = distance;
-            current_index = i;
+#[inline(always)]
+fn rgb_components(color: u32) -> (f64, f64, f64) {
+    (
+        (color >> 16 & 0xff) as f64,
+        (color >> 8 & 0xff) as f64,
+        (color & 0xff) as f64,
+    )
+}
+
+fn squared_euclidean_distance(color1: u32, color2: u32) -> f64 {
+    let (r1, g1, b1) = rgb_components(color1);
+    let (r2, g2, b2) = rgb_components(color2);
+
+    let dr = r1 - r2;
+    let dg = g1 - g2;
+    let db = b1 - b2;
+
+    dr * dr + dg * dg + db * db
+}
+
+fn redmean_distance(color1: u32, color2: u32) -> f64 {
+    let (r1, g1, b1) = rgb_components(color1);
+    let (r2, g2, b2) = rgb_components(color2);
+
+    let mean_r = (r1 + r2) / 2.0;
+    let dr = r1 - r2;
+    let dg = g1 - g2;
+    let db = b1 - b2;
+
+    ((2.0 + mean_r / 256.0) * dr * dr + 4.0 * dg * dg + (2.0 + (255.0 - mean_r) / 256.0) * db * db)
+        .sqrt()
+}
+
+type Lab = (f64, f64, f64);
+
+/// Convert an sRGB color to CIELAB using the D65 white point
+fn srgb_to_lab(color: u32) -> Lab {
+    fn linearize(value: f64) -> f64 {
+        let value = value / 255.0;
+        if value <= 0.04045 {
+            value / 12.92
+        } else {
+            ((value + 0.055) / 1.055).powf(2.4)
+        }
+    }
+
+    fn f(t: f64) -> f64 {
+        const DELTA: f64 = 6.0 / 29.0;
+        if t > DELTA * DELTA * DELTA {
+            t.cbrt()
+        } else {
+            t / (3.0 * DELTA * DELTA) + 4.0 / 29.0
+        }
+    }
+
+    let (r, g, b) = rgb_components(color);
+    let (r, g, b) = (linearize(r), linearize(g), linearize(b));
+
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+    let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+}
+
+fn delta_e_76(lab1: Lab, lab2: Lab) -> f64 {
+    let dl = lab1.0 - lab2.0;
+    let da = lab1.1 - lab2.1;
+    let db = lab1.2 - lab2.2;
+
+    (dl * dl + da * da + db * db).sqrt()
+}
+
+/// CIEDE2000 color difference as defined by Sharma, Wu and Dalal (2005)
+fn delta_e_2000(lab1: Lab, lab2: Lab) -> f64 {
+    let (l1, a1, b1) = lab1;
+    let (l2, a2, b2) = lab2;
+
+    let c1 = (a1 * a1 + b1 * b1).sqrt();
+    let c2 = (a2 * a2 + b2 * b2).sqrt();
+    let mean_c7 = ((c1 + c2) / 2.0).powi(7);
+    let g = 0.5 * (1.0 - (mean_c7 / (mean_c7 + 25f64.powi(7))).sqrt());
+
+    let a1 = (1.0 + g) * a1;
+    let a2 = (1.0 + g) * a2;
+    let c1 = (a1 * a1 + b1 * b1).sqrt();
+    let c2 = (a2 * a2 + b2 * b2).sqrt();
+
+    let hue = |a: f64, b: f64| {
+        if a == 0.0 && b == 0.0 {
+            0.0
+        } else {
+            b.atan2(a).to_degrees().rem_euclid(360.0)
+        }
+    };
+    let h1 = hue(a1, b1);
+    let h2 = hue(a2, b2);
+
+    let dl = l2 - l1;
+    let dc = c2 - c1;
+    let dh = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let dh = 2.0 * (c1 * c2).sqrt() * (dh / 2.0).to_radians().sin();
+
+    let mean_l = (l1 + l2) / 2.0;
+    let mean_c = (c1 + c2) / 2.0;
+    let mean_h = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * (mean_h - 30.0).to_radians().cos()
+        + 0.24 * (2.0 * mean_h).to_radians().cos()
+        + 0.32 * (3.0 * mean_h + 6.0).to_radians().cos()
+        - 0.20 * (4.0 * mean_h - 63.0).to_radians().cos();
+    let d_theta = 30.0 * (-((mean_h - 275.0) / 25.0).powi(2)).exp();
+    let mean_c7 = mean_c.powi(7);
+    let r_c = 2.0 * (mean_c7 / (mean_c7 + 25f64.powi(7))).sqrt();
+    let mean_l50 = (mean_l - 50.0).powi(2);
+    let s_l = 1.0 + 0.015 * mean_l50 / (20.0 + mean_l50).sqrt();
+    let s_c = 1.0 + 0.045 * mean_c;
+    let s_h = 1.0 + 0.015 * mean_c * t;
+    let r_t = -(2.0 * d_theta).to_radians().sin() * r_c;
+
+    let dl = dl / s_l;
+    let dc = dc / s_c;
+    let dh = dh / s_h;
+
+    (dl * dl + dc * dc + dh * dh + r_t * dc * dh).sqrt()
+}
+
+/// Number of entries of the cache of closest palette colors
+const PALETTE_CACHE_SIZE: usize = 4096;
+
+/// Direct mapped cache of recently looked up colors and their palette index.
+/// Neighbouring pixels often share the same color, especially in flat areas.
+struct PaletteCache {
+    entries: Vec<(u32, usize)>,
+}
+
+impl PaletteCache {
+    fn new() -> Self {
+        Self {
+            // No valid color has any of the upper bits set
+            entries: vec![(u32::MAX, 0); PALETTE_CACHE_SIZE],
         }
     }
-    current_index
+}
+
+/// Palette prepared for finding the closest color of many pixels. Colors are
+/// converted for the metric once, instead of for every comparison.
+struct PaletteLookup {
+    colors: Vec<u32>,
+    metric: ColorMetric,
+    labs: Vec<Lab>,
+}
+
+impl PaletteLookup {
+    fn new(colors: Vec<u32>, metric: ColorMetric) -> Self {
+        let labs = match metric {
+            ColorMetric::Cie76 | ColorMetric::Ciede2000 => {
+                colors.iter().map(|color| srgb_to_lab(*color)).collect()
+            }
+            _ => Vec::new(),
+        };
+
+        Self {
+            colors,
+            metric,
+            labs,
+        }
+    }
+
+    fn distances(&self, color: u32) -> impl Iterator<Item = f64> + '_ {
+        let lab = match self.metric {
+            ColorMetric::Cie76 | ColorMetric::Ciede2000 => srgb_to_lab(color),
+            _ => (0.0, 0.0, 0.0),
+        };
+
+        (0..self.colors.len()).map(move |index| match self.metric {
+            // The squared distances are integers, whose square roots never
+            // coincide. Skipping the root therefore selects the same color.
+            ColorMetric::Euclidean => squared_euclidean_distance(color, self.colors[index]),
+            ColorMetric::Redmean => redmean_distance(color, self.colors[index]),
+            ColorMetric::Cie76 => delta_e_76(lab, self.labs[index]),
+            ColorMetric::Ciede2000 => delta_e_2000(lab, self.labs[index]),
+        })
+    }
+
+    #[inline(always)]
+    fn nearest(&self, color: u32, cache: &mut PaletteCache) -> usize {
+        let slot = ((color ^ color >> 12) as usize) % PALETTE_CACHE_SIZE;
+        let (cached_color, cached_index) = cache.entries[slot];
+        if cached_color == color {
+            return cached_index;
+        }
+
+        let mut current_distance = f64::INFINITY;
+        let mut current_index = 0;
+        for (index, distance) in self.distances(color).enumerate() {
+            if distance < current_distance {
+                current_distance = distance;
+                current_index = index;
+            }
+        }
+
+        cache.entries[slot] = (color, current_index);
+        current_index
+    }
 }
 
 #[inline(always)]
@@ -146,11 +600,6 @@ where
     }
 }
 
-#[inline(always)]
-fn is_inside_image(image: &ImageBuffer<image::Rgba<u8>, Vec<u8>>, x: i64, y: i64) -> bool {
-    !(x < 0 || y < 0 || x > image.width() as i64 - 1 || y > image.height() as i64 - 1)
-}
-
 #[inline(always)]
 fn kernel_by_delta(kernel: &Kernel5x5, dx: i64, dy: i64) -> u32 {
     assert_within_range(dx, -2, 2);
@@ -168,77 +617,1007 @@ pub fn get_pixel(image: &ImageBuffer<image::Rgba<u8>, Vec<u8>>, x: u32, y: u32)
     (pixel[0] as u32) << 16 | (pixel[1] as u32) << 8 | (pixel[2] as u32) << 0
 }
 
-#[inline(always)]
-fn set_pixel(image: &mut ImageBuffer<image::Rgba<u8>, Vec<u8>>, x: u32, y: u32, new_pixel: u32) {
-    let mut pixel = image.get_pixel_mut(x, y);
-    pixel.data[0] = (new_pixel >> 16 & 0xff) as u8;
-    pixel.data[1] = (new_pixel >> 8 & 0xff) as u8;
-    pixel.data[2] = (new_pixel >> 0 & 0xff) as u8;
-    pixel.data[3] = 0xffu8;
+/// How far the accumulated error may push a channel of the working plane
+/// beyond its valid range. Without any limit, colors outside of the gamut of
+/// the palette would accumulate error endlessly and smear it across the image.
+const ERROR_DIFFUSION_MARGIN: f32 = 128.0;
+
+/// Number of columns the previous row needs to be ahead, before a pixel can be
+/// processed. Pixels receive error from up to two columns ahead in the previous
+/// rows. Those rows must have finished writing to them, before the current row
+/// starts diffusing its own error onto the same pixels.
+const ERROR_DIFFUSION_ROW_LAG: usize = 5;
+
+/// Shared state of error diffusion, which is processed by multiple workers in
+/// parallel, each one handling every n-th row.
+///
+/// Rows are pipelined: A row only advances, while the previous row is at least
+/// `ERROR_DIFFUSION_ROW_LAG` columns ahead. Therefore every pixel receives its
+/// error in exactly the same order as with sequential processing, which keeps
+/// the floating point results identical. Workers never touch the same pixels
+/// at the same time, so the atomics are only used to share the working plane.
+struct ErrorDiffusion<'a> {
+    width: usize,
+    height: usize,
+    /// RGB channels of every pixel as the bits of an f32
+    plane: Vec<AtomicU32>,
+    /// Number of pixels already processed in each row
+    progress: Vec<AtomicUsize>,
+    /// Offset and weight of each neighbour receiving error
+    weights: Vec<(i64, i64, f32)>,
+    serpentine: bool,
+    palette: &'a PaletteLookup,
+}
+
+impl<'a> ErrorDiffusion<'a> {
+    #[inline(always)]
+    fn load(&self, index: usize) -> f32 {
+        f32::from_bits(self.plane[index].load(Ordering::Relaxed))
+    }
+
+    #[inline(always)]
+    fn store(&self, index: usize, value: f32) {
+        self.plane[index].store(value.to_bits(), Ordering::Relaxed)
+    }
+
+    /// Wait until the previous row has processed the given number of pixels
+    fn wait_for_previous_row(&self, y: usize, processed: usize) -> usize {
+        let mut spins = 0u32;
+        loop {
+            let previous = self.progress[y - 1].load(Ordering::Acquire);
+            if previous >= processed {
+                return previous;
+            }
+            spins += 1;
+            if spins < 64 {
+                std::hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+    }
+
+    fn diffuse_row(&self, y: usize, row: &mut [u8], cache: &mut PaletteCache) {
+        let reverse = self.serpentine && y % 2 == 1;
+        let mut previous_processed = if y == 0 { self.width } else { 0 };
+
+        for column in 0..self.width {
+            let required = (column + ERROR_DIFFUSION_ROW_LAG).min(self.width);
+            if previous_processed < required {
+                previous_processed = self.wait_for_previous_row(y, required);
+            }
+
+            let x = if reverse {
+                self.width - 1 - column
+            } else {
+                column
+            };
+            let index = (y * self.width + x) * 3;
+            let original = [self.load(index), self.load(index + 1), self.load(index + 2)];
+
+            // Quantized pixel
+            let quantize_channel = |channel: f32| clamp(channel.round(), 0.0, 255.0) as u32;
+            let original_pixel = quantize_channel(original[0]) << 16
+                | quantize_channel(original[1]) << 8
+                | quantize_channel(original[2]);
+            let new_pixel = self.palette.colors[self.palette.nearest(original_pixel, cache)];
+            let new_channels = [
+                (new_pixel >> 16 & 0xff) as u8,
+                (new_pixel >> 8 & 0xff) as u8,
+                (new_pixel & 0xff) as u8,
+            ];
+            row[x * 4..x * 4 + 4].copy_from_slice(&[
+                new_channels[0],
+                new_channels[1],
+                new_channels[2],
+                0xff,
+            ]);
+
+            // Quantization error
+            let error = [
+                original[0] - new_channels[0] as f32,
+                original[1] - new_channels[1] as f32,
+                original[2] - new_channels[2] as f32,
+            ];
+
+            // Apply quantization error to surrounding pixels according to diffusion kernel
+            for &(kernel_dx, dy, weight) in &self.weights {
+                let dx = if reverse { -kernel_dx } else { kernel_dx };
+                let kx = x as i64 + dx;
+                let ky = y as i64 + dy;
+                if kx < 0 || kx >= self.width as i64 || ky < 0 || ky >= self.height as i64 {
+                    continue;
+                }
+
+                let neighbour = (ky as usize * self.width + kx as usize) * 3;
+                for (channel, error) in error.iter().enumerate() {
+                    self.store(
+                        neighbour + channel,
+                        clamp(
+                            self.load(neighbour + channel) + error * weight,
+                            -ERROR_DIFFUSION_MARGIN,
+                            255.0 + ERROR_DIFFUSION_MARGIN,
+                        ),
+                    );
+                }
+            }
+
+            self.progress[y].store(column + 1, Ordering::Release);
+        }
+    }
+}
+
+/// Number of workers to use for the given number of rows
+fn worker_count(rows: usize) -> usize {
+    thread::available_parallelism()
+        .map_or(1, |count| count.get())
+        .min(rows)
+        .max(1)
 }
 
+/// Diffuse the quantization error of each pixel onto its neighbours. With
+/// serpentine scanning every odd row is traversed from right to left using a
+/// mirrored kernel, which avoids directional artifacts in flat areas.
+///
+/// The error is accumulated in a floating point working plane, so that no
+/// fractions are lost and channels may temporarily leave the `0..=255` range.
+/// Only the final palette colors are written back to the image.
 pub fn apply_error_diffusion(
-    mut image: ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+    image: ImageBuffer<image::Rgba<u8>, Vec<u8>>,
     dither: Dithering,
     palette: Vec<u32>,
+    metric: ColorMetric,
+    serpentine: bool,
 ) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
-    for y in 0..image.height() {
-        for x in 0..image.width() {
-            let original_pixel = get_pixel(&image, x, y);
+    let workers = worker_count(image.height() as usize);
+    error_diffusion_with_workers(image, dither, palette, metric, serpentine, workers)
+}
 
-            // Quantized pixel
-            let nearest_palette_index = map_color_to_palette_index(original_pixel, &palette);
+fn error_diffusion_with_workers(
+    image: ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+    dither: Dithering,
+    palette: Vec<u32>,
+    metric: ColorMetric,
+    serpentine: bool,
+    workers: usize,
+) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return image;
+    }
 
-            let new_pixel = palette[nearest_palette_index];
-            set_pixel(&mut image, x, y, new_pixel);
+    let mut weights = Vec::new();
+    for dy in -2..=2 {
+        for dx in -2..=2 {
+            let kernel_value = kernel_by_delta(&dither.kernel, dx, dy);
+            if kernel_value != 0 {
+                weights.push((dx, dy, kernel_value as f32 / dither.normalization as f32));
+            }
+        }
+    }
 
-            // Quantization error
-            let er: i16 = (original_pixel >> 16 & 0xff) as i16 - (new_pixel >> 16 & 0xff) as i16;
-            let eg: i16 = (original_pixel >> 8 & 0xff) as i16 - (new_pixel >> 8 & 0xff) as i16;
-            let eb: i16 = (original_pixel >> 0 & 0xff) as i16 - (new_pixel >> 0 & 0xff) as i16;
+    // Rows scanned in opposite directions can not be pipelined
+    let workers = if serpentine { 1 } else { workers };
 
-            // Apply quantization error to surrounding pixels according to diffusion kernel
-            for dy in -2..=2 {
-                for dx in -2..=2 {
-                    let kernel_value = kernel_by_delta(&dither.kernel, dx, dy);
-
-                    let kx = i64::from(x) + dx;
-                    let ky = i64::from(y) + dy;
-
-                    if kernel_value != 0 && is_inside_image(&image, kx, ky) {
-                        // Original not yet diffused pixel
-                        let original = get_pixel(&image, kx as u32, ky as u32);
-
-                        // Pixel with propagated error
-                        let dr = clamp(
-                            (original >> 16 & 0xff) as i64
-                                + (er as i64 * kernel_value as i64 / dither.normalization as i64),
-                            0,
-                            255,
-                        ) as u8;
-                        let dg = clamp(
-                            (original >> 8 & 0xff) as i64
-                                + (eg as i64 * kernel_value as i64 / dither.normalization as i64),
-                            0,
-                            255,
-                        ) as u8;
-                        let db = clamp(
-                            (original >> 0 & 0xff) as i64
-                                + (eb as i64 * kernel_value as i64 / dither.normalization as i64),
-                            0,
-                            255,
-                        ) as u8;
-
-                        set_pixel(
-                            &mut image,
-                            kx as u32,
-                            ky as u32,
-                            (dr as u32) << 16 | (dg as u32) << 8 | (db as u32) << 0,
+    let palette = PaletteLookup::new(palette, metric);
+    let mut buffer = image.into_raw();
+    let diffusion = ErrorDiffusion {
+        width: width as usize,
+        height: height as usize,
+        plane: buffer
+            .chunks(4)
+            .flat_map(|pixel| pixel[..3].iter())
+            .map(|channel| AtomicU32::new((*channel as f32).to_bits()))
+            .collect(),
+        progress: (0..height).map(|_| AtomicUsize::new(0)).collect(),
+        weights,
+        serpentine,
+        palette: &palette,
+    };
+
+    let mut worker_rows: Vec<Vec<(usize, &mut [u8])>> = (0..workers).map(|_| Vec::new()).collect();
+    for (y, row) in buffer.chunks_mut(width as usize * 4).enumerate() {
+        worker_rows[y % workers].push((y, row));
+    }
+
+    thread::scope(|scope| {
+        for rows in worker_rows {
+            let diffusion = &diffusion;
+            scope.spawn(move || {
+                let mut cache = PaletteCache::new();
+                for (y, row) in rows {
+                    diffusion.diffuse_row(y, row, &mut cache);
+                }
+            });
+        }
+    });
+
+    ImageBuffer::from_raw(width, height, buffer).unwrap()
+}
+
+/// Amount each color channel is offset by the threshold map during ordered
+/// dithering. Half the range of a channel, as the palettes only provide about
+/// two levels per channel.
+const ORDERED_DITHERING_SPREAD: f64 = 128.0;
+
+/// Size of the generated blue noise threshold map
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Seed of the initial pattern of the blue noise threshold map
+const BLUE_NOISE_SEED: u64 = 1993;
+
+/// Threshold map used for ordered dithering, which is tiled across the image.
+/// Each threshold lies within `[0, 1)`.
+#[derive(Debug, Clone)]
+pub struct ThresholdMap {
+    width: usize,
+    height: usize,
+    thresholds: Vec<f64>,
+}
+
+impl ThresholdMap {
+    /// Create a map from the rank of each position, where the ranks are a
+    /// permutation of `0..width * height`.
+    fn from_ranks(width: usize, height: usize, ranks: &[usize]) -> Self {
+        let count = (width * height) as f64;
+        Self {
+            width,
+            height,
+            thresholds: ranks
+                .iter()
+                .map(|rank| (*rank as f64 + 0.5) / count)
+                .collect(),
+        }
+    }
+
+    #[inline(always)]
+    fn threshold(&self, x: u32, y: u32) -> f64 {
+        self.thresholds[(y as usize % self.height) * self.width + (x as usize % self.width)]
+    }
+}
+
+/// Bayer matrix of the given size, which must be a power of two
+pub fn bayer(size: usize) -> ThresholdMap {
+    assert!(size.is_power_of_two(), "Bayer size must be a power of two");
+
+    let mut ranks = vec![0];
+    let mut current_size = 1;
+    while current_size < size {
+        let next_size = current_size * 2;
+        let mut next_ranks = vec![0; next_size * next_size];
+        for y in 0..current_size {
+            for x in 0..current_size {
+                let rank = 4 * ranks[y * current_size + x];
+                next_ranks[y * next_size + x] = rank;
+                next_ranks[y * next_size + x + current_size] = rank + 2;
+                next_ranks[(y + current_size) * next_size + x] = rank + 3;
+                next_ranks[(y + current_size) * next_size + x + current_size] = rank + 1;
+            }
+        }
+        ranks = next_ranks;
+        current_size = next_size;
+    }
+
+    ThresholdMap::from_ranks(size, size, &ranks)
+}
+
+/// Blue noise threshold map, which is generated once on first use
+pub fn blue_noise() -> &'static ThresholdMap {
+    static BLUE_NOISE: OnceLock<ThresholdMap> = OnceLock::new();
+    BLUE_NOISE.get_or_init(|| void_and_cluster(BLUE_NOISE_SIZE))
+}
+
+/// Energy of a binary pattern at each position, which is the sum of a gaussian
+/// of the toroidal distance to all set positions.
+#[derive(Clone)]
+struct PatternEnergy {
+    size: usize,
+    gaussian: Vec<f64>,
+    energy: Vec<f64>,
+}
+
+impl PatternEnergy {
+    fn new(size: usize) -> Self {
+        const SIGMA: f64 = 1.5;
+
+        let mut gaussian = vec![0.0; size * size];
+        for dy in 0..size {
+            for dx in 0..size {
+                let wrapped_dx = dx.min(size - dx) as f64;
+                let wrapped_dy = dy.min(size - dy) as f64;
+                gaussian[dy * size + dx] = (-(wrapped_dx * wrapped_dx + wrapped_dy * wrapped_dy)
+                    / (2.0 * SIGMA * SIGMA))
+                    .exp();
+            }
+        }
+
+        Self {
+            size,
+            gaussian,
+            energy: vec![0.0; size * size],
+        }
+    }
+
+    fn update(&mut self, position: usize, sign: f64) {
+        let (px, py) = (position % self.size, position / self.size);
+        for y in 0..self.size {
+            let dy = (y + self.size - py) % self.size;
+            for x in 0..self.size {
+                let dx = (x + self.size - px) % self.size;
+                self.energy[y * self.size + x] += sign * self.gaussian[dy * self.size + dx];
+            }
+        }
+    }
+
+    /// Set position with the highest energy
+    fn tightest_cluster(&self, pattern: &[bool]) -> usize {
+        (0..pattern.len())
+            .filter(|&i| pattern[i])
+            .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+
+    /// Unset position with the lowest energy
+    fn largest_void(&self, pattern: &[bool]) -> usize {
+        (0..pattern.len())
+            .filter(|&i| !pattern[i])
+            .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+            .unwrap()
+    }
+}
+
+/// Generate a blue noise threshold map using the void-and-cluster method by
+/// Ulichney (1993). A fixed seed is used, to always produce the same map.
+fn void_and_cluster(size: usize) -> ThresholdMap {
+    let count = size * size;
+    let mut rng = StdRng::seed_from_u64(BLUE_NOISE_SEED);
+
+    // Initial binary pattern with about a tenth of all positions set, spread
+    // evenly by repeatedly moving the tightest cluster into the largest void
+    let mut initial = vec![false; count];
+    let mut energy = PatternEnergy::new(size);
+    for position in rand::seq::index::sample(&mut rng, count, count / 10) {
+        initial[position] = true;
+        energy.update(position, 1.0);
+    }
+    loop {
+        let cluster = energy.tightest_cluster(&initial);
+        initial[cluster] = false;
+        energy.update(cluster, -1.0);
+        let void = energy.largest_void(&initial);
+        initial[void] = true;
+        energy.update(void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+    let initial_ones = initial.iter().filter(|set| **set).count();
+
+    let mut ranks = vec![0; count];
+
+    // Rank the positions of the initial pattern by removing the tightest
+    // clusters first
+    let mut pattern = initial.clone();
+    let mut removal_energy = energy.clone();
+    for rank in (0..initial_ones).rev() {
+        let cluster = removal_energy.tightest_cluster(&pattern);
+        pattern[cluster] = false;
+        removal_energy.update(cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+
+    // Rank the remaining positions by filling the largest voids first
+    let mut pattern = initial;
+    for rank in initial_ones..count {
+        let void = energy.largest_void(&pattern);
+        pattern[void] = true;
+        energy.update(void, 1.0);
+        ranks[void] = rank;
+    }
+
+    ThresholdMap::from_ranks(size, size, &ranks)
+}
+
+/// Dither the image by offsetting each pixel according to the tiled threshold
+/// map, before mapping it to the closest palette color. In contrast to error
+/// diffusion every pixel is handled independently, so bands of rows are
+/// processed in parallel.
+pub fn apply_ordered_dithering(
+    image: ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+    threshold_map: &ThresholdMap,
+    palette: Vec<u32>,
+    metric: ColorMetric,
+) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return image;
+    }
+
+    let palette = PaletteLookup::new(palette, metric);
+    let mut buffer = image.into_raw();
+    let rows_per_band = (height as usize).div_ceil(worker_count(height as usize));
+
+    thread::scope(|scope| {
+        for (band, rows) in buffer
+            .chunks_mut(width as usize * 4 * rows_per_band)
+            .enumerate()
+        {
+            let palette = &palette;
+            scope.spawn(move || {
+                let mut cache = PaletteCache::new();
+                for (row, pixel) in rows.chunks_mut(4).enumerate() {
+                    let x = (row % width as usize) as u32;
+                    let y = (band * rows_per_band + row / width as usize) as u32;
+                    let offset = ORDERED_DITHERING_SPREAD * (threshold_map.threshold(x, y) - 0.5);
+                    let offset_channel =
+                        |channel: u8| clamp(channel as f64 + offset, 0.0, 255.0).round() as u32;
+                    let offset_pixel = offset_channel(pixel[0]) << 16
+                        | offset_channel(pixel[1]) << 8
+                        | offset_channel(pixel[2]);
+
+                    let new_pixel = palette.colors[palette.nearest(offset_pixel, &mut cache)];
+                    pixel.copy_from_slice(&[
+                        (new_pixel >> 16 & 0xff) as u8,
+                        (new_pixel >> 8 & 0xff) as u8,
+                        (new_pixel & 0xff) as u8,
+                        0xff,
+                    ]);
+                }
+            });
+        }
+    });
+
+    ImageBuffer::from_raw(width, height, buffer).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn color_distance(metric: ColorMetric, color1: u32, color2: u32) -> f64 {
+        match metric {
+            ColorMetric::Euclidean => squared_euclidean_distance(color1, color2).sqrt(),
+            ColorMetric::Redmean => redmean_distance(color1, color2),
+            ColorMetric::Cie76 => delta_e_76(srgb_to_lab(color1), srgb_to_lab(color2)),
+            ColorMetric::Ciede2000 => delta_e_2000(srgb_to_lab(color1), srgb_to_lab(color2)),
+        }
+    }
+
+    fn map_color_to_palette_index(color: u32, palette: &[u32], metric: ColorMetric) -> usize {
+        let mut current_distance = f64::INFINITY;
+        let mut current_index = 0;
+        for (index, palette_color) in palette.iter().enumerate() {
+            let distance = color_distance(metric, color, *palette_color);
+            if distance < current_distance {
+                current_distance = distance;
+                current_index = index;
+            }
+        }
+        current_index
+    }
+
+    #[inline(always)]
+    fn is_inside_image(image: &ImageBuffer<image::Rgba<u8>, Vec<u8>>, x: i64, y: i64) -> bool {
+        !(x < 0 || y < 0 || x > image.width() as i64 - 1 || y > image.height() as i64 - 1)
+    }
+
+    #[inline(always)]
+    fn set_pixel(
+        image: &mut ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+        x: u32,
+        y: u32,
+        new_pixel: u32,
+    ) {
+        let pixel = image.get_pixel_mut(x, y);
+        pixel.data[0] = (new_pixel >> 16 & 0xff) as u8;
+        pixel.data[1] = (new_pixel >> 8 & 0xff) as u8;
+        pixel.data[2] = (new_pixel & 0xff) as u8;
+        pixel.data[3] = 0xffu8;
+    }
+
+    /// Straightforward sequential implementation, which the optimized one
+    /// has to match exactly
+    fn reference_error_diffusion(
+        mut image: ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+        dither: Dithering,
+        palette: Vec<u32>,
+        metric: ColorMetric,
+        serpentine: bool,
+    ) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+        let width = image.width();
+        let mut plane: Vec<[f32; 3]> = image
+            .pixels()
+            .map(|pixel| {
+                [
+                    pixel.data[0] as f32,
+                    pixel.data[1] as f32,
+                    pixel.data[2] as f32,
+                ]
+            })
+            .collect();
+
+        for y in 0..image.height() {
+            let reverse = serpentine && y % 2 == 1;
+            for column in 0..width {
+                let x = if reverse { width - 1 - column } else { column };
+                let original = plane[(y * width + x) as usize];
+                let quantize_channel = |channel: f32| clamp(channel.round(), 0.0, 255.0) as u32;
+                let original_pixel = quantize_channel(original[0]) << 16
+                    | quantize_channel(original[1]) << 8
+                    | quantize_channel(original[2]);
+
+                // Quantized pixel
+                let nearest_palette_index =
+                    map_color_to_palette_index(original_pixel, &palette, metric);
+
+                let new_pixel = palette[nearest_palette_index];
+                set_pixel(&mut image, x, y, new_pixel);
+
+                // Quantization error
+                let error = [
+                    original[0] - (new_pixel >> 16 & 0xff) as f32,
+                    original[1] - (new_pixel >> 8 & 0xff) as f32,
+                    original[2] - (new_pixel & 0xff) as f32,
+                ];
+
+                // Apply quantization error to surrounding pixels according to diffusion kernel
+                for dy in -2..=2 {
+                    for dx in -2..=2 {
+                        let kernel_dx = if reverse { -dx } else { dx };
+                        let kernel_value = kernel_by_delta(&dither.kernel, kernel_dx, dy);
+
+                        let kx = i64::from(x) + dx;
+                        let ky = i64::from(y) + dy;
+
+                        if kernel_value != 0 && is_inside_image(&image, kx, ky) {
+                            let weight = kernel_value as f32 / dither.normalization as f32;
+                            let neighbour = &mut plane[(ky * i64::from(width) + kx) as usize];
+                            for channel in 0..3 {
+                                neighbour[channel] = clamp(
+                                    neighbour[channel] + error[channel] * weight,
+                                    -ERROR_DIFFUSION_MARGIN,
+                                    255.0 + ERROR_DIFFUSION_MARGIN,
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    fn reference_ordered_dithering(
+        mut image: ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+        threshold_map: &ThresholdMap,
+        palette: Vec<u32>,
+        metric: ColorMetric,
+    ) -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+        for y in 0..image.height() {
+            for x in 0..image.width() {
+                let original_pixel = get_pixel(&image, x, y);
+                let offset = ORDERED_DITHERING_SPREAD * (threshold_map.threshold(x, y) - 0.5);
+                let offset_channel = |channel: u32| {
+                    clamp((channel & 0xff) as f64 + offset, 0.0, 255.0).round() as u32
+                };
+                let offset_pixel = offset_channel(original_pixel >> 16) << 16
+                    | offset_channel(original_pixel >> 8) << 8
+                    | offset_channel(original_pixel);
+
+                let nearest_palette_index =
+                    map_color_to_palette_index(offset_pixel, &palette, metric);
+                set_pixel(&mut image, x, y, palette[nearest_palette_index]);
+            }
+        }
+        image
+    }
+
+    const BLACK: usize = 0;
+    const WHITE: usize = 1;
+    const BLUE: usize = 3;
+    const RED: usize = 4;
+    const YELLOW: usize = 5;
+    const ORANGE: usize = 6;
+
+    const METRICS: [ColorMetric; 4] = [
+        ColorMetric::Euclidean,
+        ColorMetric::Redmean,
+        ColorMetric::Cie76,
+        ColorMetric::Ciede2000,
+    ];
+
+    fn acep_index(color: u32, metric: ColorMetric) -> usize {
+        map_color_to_palette_index(color, &palette_7_acep(), metric)
+    }
+
+    #[test]
+    fn maps_palette_colors_to_themselves() {
+        for metric in METRICS {
+            for (index, color) in palette_7_acep().into_iter().enumerate() {
+                assert_eq!(acep_index(color, metric), index, "{:?}", metric);
+            }
+        }
+    }
+
+    #[test]
+    fn maps_known_colors_to_palette_index() {
+        for metric in METRICS {
+            // Skin tone
+            assert_eq!(acep_index(0xe0ac69, metric), ORANGE, "{:?}", metric);
+            // Sky blue
+            assert_eq!(acep_index(0x87ceeb, metric), WHITE, "{:?}", metric);
+            // Slate blue
+            assert_eq!(acep_index(0x6a5acd, metric), BLUE, "{:?}", metric);
+            // Dark olive green
+            assert_eq!(acep_index(0x556b2f, metric), BLACK, "{:?}", metric);
+        }
+
+        // Medium gray
+        assert_eq!(acep_index(0x808080, ColorMetric::Euclidean), ORANGE);
+        assert_eq!(acep_index(0x808080, ColorMetric::Cie76), WHITE);
+        // Maroon
+        assert_eq!(acep_index(0x800000, ColorMetric::Euclidean), RED);
+        assert_eq!(acep_index(0x800000, ColorMetric::Redmean), BLACK);
+        assert_eq!(acep_index(0x800000, ColorMetric::Ciede2000), RED);
+        // Olive
+        assert_eq!(acep_index(0x7f7f00, ColorMetric::Euclidean), ORANGE);
+        assert_eq!(acep_index(0x7f7f00, ColorMetric::Ciede2000), YELLOW);
+    }
+
+    #[test]
+    fn converts_srgb_to_lab() {
+        let (l, a, b) = srgb_to_lab(0xffffff);
+        assert!((l - 100.0).abs() < 1e-4);
+        assert!(a.abs() < 1e-4);
+        assert!(b.abs() < 1e-4);
+
+        let (l, a, b) = srgb_to_lab(0xff0000);
+        assert!((l - 53.24).abs() < 0.01);
+        assert!((a - 80.09).abs() < 0.01);
+        assert!((b - 67.20).abs() < 0.01);
+    }
+
+    #[test]
+    fn calculates_ciede2000_reference_differences() {
+        // Test data by Sharma, Wu and Dalal
+        let pairs = [
+            ((50.0, 2.6772, -79.7751), (50.0, 0.0, -82.7485), 2.0425),
+            ((50.0, 2.5, 0.0), (73.0, 25.0, -18.0), 27.1492),
+            ((50.0, 2.5, 0.0), (50.0, 0.0, -2.5), 4.3065),
+            (
+                (60.2574, -34.0099, 36.2677),
+                (60.4626, -34.1751, 39.4387),
+                1.2644,
+            ),
+            (
+                (22.7233, 20.0904, -46.6940),
+                (23.0331, 14.9730, -42.5619),
+                2.0373,
+            ),
+        ];
+
+        for (lab1, lab2, expected) in pairs {
+            assert!((delta_e_2000(lab1, lab2) - expected).abs() < 1e-4);
+            assert!((delta_e_2000(lab2, lab1) - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn parses_color_metric_names() {
+        assert_eq!(
+            "ciede2000".parse::<ColorMetric>().unwrap(),
+            ColorMetric::Ciede2000
+        );
+        assert_eq!(
+            "redmean".parse::<ColorMetric>().unwrap(),
+            ColorMetric::Redmean
+        );
+        assert!("cie94".parse::<ColorMetric>().is_err());
+    }
+
+    /// Gradient across all channels, which covers all palette colors
+    fn gradient_image() -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(16, 8, |x, y| {
+            image::Rgba([
+                (x * 16) as u8,
+                (y * 32) as u8,
+                (255 - x * 12 - y * 8) as u8,
+                255,
+            ])
+        })
+    }
+
+    /// Dither the gradient and render the palette index of every pixel
+    fn dithered_indices(algorithm: DitheringAlgorithm, serpentine: bool) -> Vec<String> {
+        let palette = palette_7_acep();
+        let dithered = algorithm.apply(
+            gradient_image(),
+            palette.clone(),
+            ColorMetric::Euclidean,
+            serpentine,
+        );
+
+        (0..dithered.height())
+            .map(|y| {
+                (0..dithered.width())
+                    .map(|x| {
+                        let pixel = get_pixel(&dithered, x, y);
+                        let index = palette.iter().position(|c| *c == pixel).unwrap();
+                        char::from_digit(index as u32, 10).unwrap()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dithers_with_stucki() {
+        assert_eq!(
+            dithered_indices(DitheringAlgorithm::Stucki, false),
+            [
+                "3333333343444444",
+                "3333343434334434",
+                "3333636366366146",
+                "3321331363663666",
+                "3232326136166666",
+                "2313213221631616",
+                "3212121212626666",
+                "2123212126152155",
+            ]
+        );
+    }
+
+    #[test]
+    fn dithers_with_burkes() {
+        assert_eq!(
+            dithered_indices(DitheringAlgorithm::Burkes, false),
+            [
+                "3333333434344444",
+                "3333434343434344",
+                "3333313636146146",
+                "3213236363636466",
+                "3332312136163616",
+                "2123123622616666",
+                "3321231213626216",
+                "2112322125151555",
+            ]
+        );
+    }
+
+    #[test]
+    fn dithers_with_sierra3() {
+        assert_eq!(
+            dithered_indices(DitheringAlgorithm::Sierra3, false),
+            [
+                "3333333343444444",
+                "3333343434334444",
+                "3333636366631636",
+                "3321331331666666",
+                "3233226636363666",
+                "3123132126161666",
+                "2321213212266165",
+                "2112212211262555",
+            ]
+        );
+    }
+
+    #[test]
+    fn dithers_with_sierra2() {
+        assert_eq!(
+            dithered_indices(DitheringAlgorithm::Sierra2, false),
+            [
+                "3333333434434444",
+                "3333433433443444",
+                "3333631636631641",
+                "3231323361366464",
+                "3323123663663616",
+                "3213213212616666",
+                "2312321216212655",
+                "2112212321626156",
+            ]
+        );
+    }
+
+    #[test]
+    fn dithers_with_sierra_lite() {
+        assert_eq!(
+            dithered_indices(DitheringAlgorithm::SierraLite, false),
+            [
+                "3333334343434444",
+                "3333433343443414",
+                "3323314314361444",
+                "3313236363536414",
+                "2323132101663666",
+                "3132310123516616",
+                "2321232126262626",
+                "1112121212161555",
+            ]
+        );
+    }
+
+    #[test]
+    fn dithers_serpentine_with_mirrored_kernel() {
+        assert_eq!(
+            dithered_indices(DitheringAlgorithm::JarvisJudiceNinke, true),
+            [
+                "3333333343444444",
+                "3333333434334344",
+                "3336636636661466",
+                "3233313313663666",
+                "3321326361366166",
+                "2312322162661666",
+                "2321312312216266",
+                "1112212212162155",
+            ]
+        );
+    }
+
+    /// Mean color of the image as floating point channels
+    fn mean_color(image: &ImageBuffer<image::Rgba<u8>, Vec<u8>>) -> [f64; 3] {
+        let mut sum = [0.0; 3];
+        for pixel in image.pixels() {
+            for (sum, value) in sum.iter_mut().zip(pixel.data) {
+                *sum += value as f64;
+            }
+        }
+        let count = (image.width() * image.height()) as f64;
+        sum.map(|channel| channel / count)
+    }
+
+    #[test]
+    fn preserves_mean_color_of_flat_patch() {
+        let patches = [
+            (0x808080, palette_8_grayscale()),
+            (0x303030, palette_7_acep()),
+            (0x996633, palette_7_acep()),
+            (0x4080c0, palette_7_acep()),
+            (0x8a6a5a, palette_7_acep_calibrated()),
+        ];
+
+        for (color, palette) in patches {
+            let (r, g, b) = rgb_components(color);
+            let patch =
+                ImageBuffer::from_pixel(64, 64, image::Rgba([r as u8, g as u8, b as u8, 255]));
+            let dithered = apply_error_diffusion(
+                patch,
+                floyd_steinberg(),
+                palette,
+                ColorMetric::Euclidean,
+                false,
+            );
+
+            let mean = mean_color(&dithered);
+            for (actual, expected) in mean.iter().zip([r, g, b]) {
+                assert!(
+                    (actual - expected).abs() < 2.0,
+                    "{:06x}: mean {:?}",
+                    color,
+                    mean
+                );
+            }
+        }
+    }
+
+    /// Gradient with random noise, using odd dimensions
+    fn noisy_image() -> ImageBuffer<image::Rgba<u8>, Vec<u8>> {
+        let mut rng = StdRng::seed_from_u64(42);
+        ImageBuffer::from_fn(61, 37, |x, y| {
+            let mut noisy =
+                |value: u32| (value as i32 + rng.gen_range(-40..=40)).clamp(0, 255) as u8;
+            image::Rgba([noisy(x * 4), noisy(y * 7), noisy(255 - x * 2 - y * 3), 255])
+        })
+    }
+
+    #[test]
+    fn error_diffusion_matches_reference() {
+        let algorithms = [
+            DitheringAlgorithm::JarvisJudiceNinke,
+            DitheringAlgorithm::FloydSteinberg,
+            DitheringAlgorithm::Atkinson,
+            DitheringAlgorithm::Stucki,
+            DitheringAlgorithm::Burkes,
+            DitheringAlgorithm::Sierra3,
+            DitheringAlgorithm::Sierra2,
+            DitheringAlgorithm::SierraLite,
+            DitheringAlgorithm::None,
+        ];
+
+        for algorithm in algorithms {
+            let kernel = algorithm.kernel().unwrap();
+            for metric in METRICS {
+                for serpentine in [false, true] {
+                    let expected = reference_error_diffusion(
+                        noisy_image(),
+                        kernel,
+                        palette_7_acep_calibrated(),
+                        metric,
+                        serpentine,
+                    );
+                    for workers in [1, 2, 3, 8] {
+                        let actual = error_diffusion_with_workers(
+                            noisy_image(),
+                            kernel,
+                            palette_7_acep_calibrated(),
+                            metric,
+                            serpentine,
+                            workers,
+                        );
+                        assert!(
+                            actual.into_raw() == expected.clone().into_raw(),
+                            "{:?} {:?} serpentine: {} workers: {}",
+                            algorithm,
+                            metric,
+                            serpentine,
+                            workers
                         );
                     }
                 }
             }
         }
     }
-    image
+
+    /// Ranks of all positions, recovered from their thresholds
+    fn ranks(threshold_map: &ThresholdMap) -> Vec<usize> {
+        let count = (threshold_map.width * threshold_map.height) as f64;
+        threshold_map
+            .thresholds
+            .iter()
+            .map(|threshold| (threshold * count - 0.5).round() as usize)
+            .collect()
+    }
+
+    #[test]
+    fn builds_bayer_matrices() {
+        assert_eq!(ranks(&bayer(2)), vec![0, 2, 3, 1]);
+        assert_eq!(
+            ranks(&bayer(4)),
+            vec![0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5]
+        );
+    }
+
+    #[test]
+    fn ranks_each_blue_noise_position_once() {
+        let mut ranks = ranks(blue_noise());
+        ranks.sort_unstable();
+        assert_eq!(
+            ranks,
+            (0..BLUE_NOISE_SIZE * BLUE_NOISE_SIZE).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn ordered_dithering_preserves_mean_of_flat_patch() {
+        for threshold_map in [&bayer(4), &bayer(8), blue_noise()] {
+            let patch = ImageBuffer::from_pixel(64, 64, image::Rgba([128, 128, 128, 255]));
+            let dithered = apply_ordered_dithering(
+                patch,
+                threshold_map,
+                palette_2_monochrome(),
+                ColorMetric::Euclidean,
+            );
+
+            let mean = mean_color(&dithered);
+            for actual in mean {
+                assert!((actual - 128.0).abs() < 2.0, "mean {:?}", mean);
+            }
+        }
+    }
+
+    #[test]
+    fn ordered_dithering_matches_reference() {
+        for threshold_map in [&bayer(2), &bayer(4), &bayer(8), blue_noise()] {
+            for metric in METRICS {
+                let expected = reference_ordered_dithering(
+                    noisy_image(),
+                    threshold_map,
+                    palette_7_acep(),
+                    metric,
+                );
+                let actual =
+                    apply_ordered_dithering(noisy_image(), threshold_map, palette_7_acep(), metric);
+                assert!(actual.into_raw() == expected.into_raw(), "{:?}", metric);
+            }
+        }
+    }
 }
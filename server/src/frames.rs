@@ -0,0 +1,156 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::ImageRequest;
+
+/// Name of the device, if none is given by the client
+pub const DEFAULT_DEVICE: &str = "default";
+
+/// Frames expire a little early. The clock of a device is not accurate during
+/// deep sleep, so it might wake up before the update interval has passed.
+const FRAME_EXPIRY_TOLERANCE: Duration = Duration::from_secs(60);
+
+/// Frame currently shown by a device
+#[derive(Clone)]
+pub struct CurrentFrame {
+    /// Identifies the content of the frame, used as entity tag
+    pub etag: String,
+    /// Request the frame has been created for
+    pub request: ImageRequest,
+    /// Uncompressed frame as sent to the display
+    pub inkplate: Vec<u8>,
+    pub created_at: SystemTime,
+}
+
+impl CurrentFrame {
+    fn new(request: ImageRequest, inkplate: Vec<u8>, created_at: SystemTime) -> Self {
+        let mut hasher = DefaultHasher::new();
+        inkplate.hash(&mut hasher);
+        Self {
+            etag: format!("{:016x}", hasher.finish()),
+            request,
+            inkplate,
+            created_at,
+        }
+    }
+
+    /// Whether the value of an `If-None-Match` header refers to this frame.
+    /// The header may contain a list of (weak) entity tags, or `*`.
+    pub fn matches(&self, if_none_match: &str) -> bool {
+        if_none_match
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/").trim_matches('"') == self.etag)
+    }
+}
+
+/// Frames currently shown by the devices, identified by their name.
+///
+/// A device keeps its frame, until it is older than the update interval or is
+/// requested with different parameters. Only then a new image is picked.
+#[derive(Default)]
+pub struct CurrentFrames {
+    frames: Mutex<HashMap<String, CurrentFrame>>,
+}
+
+impl CurrentFrames {
+    /// The frame of the device, if it is still valid for the given request
+    pub fn current(
+        &self,
+        device: &str,
+        request: &ImageRequest,
+        max_age: Duration,
+        now: SystemTime,
+    ) -> Option<CurrentFrame> {
+        let frames = self.frames.lock().unwrap();
+        let frame = frames.get(device)?;
+        let age = now.duration_since(frame.created_at).unwrap_or_default();
+        if frame.request != *request || age >= max_age.saturating_sub(FRAME_EXPIRY_TOLERANCE) {
+            return None;
+        }
+        Some(frame.clone())
+    }
+
+    /// Make the given frame the current one of the device
+    pub fn replace(
+        &self,
+        device: &str,
+        request: ImageRequest,
+        inkplate: Vec<u8>,
+        now: SystemTime,
+    ) -> CurrentFrame {
+        let frame = CurrentFrame::new(request, inkplate, now);
+        self.frames
+            .lock()
+            .unwrap()
+            .insert(device.to_string(), frame.clone());
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PersistedConfig;
+
+    const INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+    fn request(search: &str) -> ImageRequest {
+        ImageRequest {
+            search: Some(search.to_string()),
+            ..PersistedConfig::default().default_request()
+        }
+    }
+
+    #[test]
+    fn matches_entity_tags() {
+        let frame = CurrentFrame::new(request("cats"), vec![1, 2, 3], SystemTime::UNIX_EPOCH);
+        let etag = frame.etag.clone();
+        assert!(frame.matches(&format!("\"{}\"", etag)));
+        assert!(frame.matches(&format!("W/\"{}\"", etag)));
+        assert!(frame.matches(&format!("\"other\", W/\"{}\"", etag)));
+        assert!(frame.matches("*"));
+        assert!(!frame.matches("\"other\""));
+        assert!(!frame.matches(""));
+    }
+
+    #[test]
+    fn keeps_frame_until_it_expires() {
+        let frames = CurrentFrames::default();
+        let created_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let frame = frames.replace("kitchen", request("cats"), vec![1], created_at);
+
+        let current = frames.current("kitchen", &request("cats"), INTERVAL, created_at);
+        assert_eq!(current.unwrap().etag, frame.etag);
+        // Expires early, within the tolerance of the clock of the device
+        let almost = created_at + INTERVAL - FRAME_EXPIRY_TOLERANCE - Duration::from_secs(1);
+        assert!(frames
+            .current("kitchen", &request("cats"), INTERVAL, almost)
+            .is_some());
+        let expired = created_at + INTERVAL - FRAME_EXPIRY_TOLERANCE;
+        assert!(frames
+            .current("kitchen", &request("cats"), INTERVAL, expired)
+            .is_none());
+    }
+
+    #[test]
+    fn replaces_frame_on_other_request() {
+        let frames = CurrentFrames::default();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        frames.replace("kitchen", request("cats"), vec![1], now);
+
+        assert!(frames
+            .current("kitchen", &request("dogs"), INTERVAL, now)
+            .is_none());
+        assert!(frames
+            .current("hallway", &request("cats"), INTERVAL, now)
+            .is_none());
+
+        frames.replace("kitchen", request("dogs"), vec![2], now);
+        let current = frames.current("kitchen", &request("dogs"), INTERVAL, now);
+        assert_eq!(current.unwrap().inkplate, vec![2]);
+    }
+}
@@ -1,9 +1,12 @@
 use image::{DynamicImage, GenericImage};
 use jpegxl_rs::encode::{EncoderResult, EncoderSpeed};
-use jpegxl_rs::encoder_builder;
+use jpegxl_rs::{decoder_builder, encoder_builder};
+use rusttype::{point, Font, Scale};
+use std::fs;
 use std::time::Instant;
 
-use crate::dithering;
+use crate::display::Packing;
+use crate::dithering::{self, ColorMetric, DitheringAlgorithm, Palette};
 
 fn get_cover_dimensions(
     width: u32,
@@ -26,10 +29,12 @@ fn get_cover_dimensions(
     }
 }
 
-pub fn scale_and_crop_image(image: &image::DynamicImage) -> image::DynamicImage {
+pub fn scale_and_crop_image(
+    image: &image::DynamicImage,
+    target_width: u32,
+    target_height: u32,
+) -> image::DynamicImage {
     let (width, height) = image.dimensions();
-    let target_width = 448u32;
-    let target_height = 600u32;
 
     let (new_width, new_height) = get_cover_dimensions(width, height, target_width, target_height);
 
@@ -87,106 +92,284 @@ pub fn png(image: &DynamicImage) -> Vec<u8> {
     out_bytes
 }
 
-pub fn jpegxl(image: &DynamicImage) -> Vec<u8> {
+pub fn jpegxl(image: &DynamicImage) -> anyhow::Result<Vec<u8>> {
     let start = Instant::now();
 
     let raw_image = image.to_rgb().into_raw();
     let mut encoder = encoder_builder()
         .lossless(true)
         .speed(EncoderSpeed::Falcon)
-        .build()
-        .unwrap();
+        .build()?;
 
-    let encodedu8: EncoderResult<u8> = encoder
-        .encode(&raw_image, image.width(), image.height())
-        .unwrap();
+    let encodedu8: EncoderResult<u8> = encoder.encode(&raw_image, image.width(), image.height())?;
 
     let end = Instant::now();
     println!("jpegxl encoding took {:?}", end - start);
 
-    encodedu8.data
+    Ok(encodedu8.data)
 }
 
-pub fn jpegxl_from_data(image_data: &Vec<u8>) -> Vec<u8> {
-    let image = image::load_from_memory(&image_data).unwrap();
+pub fn image_from_jpegxl(data: &[u8]) -> anyhow::Result<DynamicImage> {
+    let decoder = decoder_builder().build()?;
+    let (metadata, pixels) = decoder.decode_with::<u8>(data)?;
+
+    let image = if metadata.has_alpha_channel {
+        image::RgbaImage::from_raw(metadata.width, metadata.height, pixels)
+            .map(DynamicImage::ImageRgba8)
+    } else {
+        image::RgbImage::from_raw(metadata.width, metadata.height, pixels)
+            .map(DynamicImage::ImageRgb8)
+    };
+
+    image.ok_or_else(|| anyhow::anyhow!("Decoded jpegxl data does not match its dimensions"))
+}
+
+pub fn jpegxl_from_data(image_data: &Vec<u8>) -> anyhow::Result<Vec<u8>> {
+    let image = image::load_from_memory(&image_data)?;
     jpegxl(&image)
 }
 
-pub fn image_dithered(image: &DynamicImage) -> DynamicImage {
-    let dithered = dithering::apply_error_diffusion(
+pub fn font_from_file(path: &str) -> anyhow::Result<Font<'static>> {
+    Font::try_from_vec(fs::read(path)?)
+        .ok_or_else(|| anyhow::anyhow!("Could not load font from {}", path))
+}
+
+/// Draw the lines of text onto the bottom left corner of the image, each on a
+/// white box, using the given TrueType font. Text exceeding the image is cut
+/// off.
+pub fn draw_text_lines(
+    image: &DynamicImage,
+    lines: &[String],
+    font: &Font,
+    font_size: u32,
+) -> DynamicImage {
+    let mut drawn = image.to_rgba();
+    let (width, height) = drawn.dimensions();
+    let scale = Scale::uniform(font_size as f32);
+    let v_metrics = font.v_metrics(scale);
+    let line_height = (v_metrics.ascent - v_metrics.descent).ceil() as i32;
+    let padding = (font_size / 4) as i32;
+
+    let mut bottom = height as i32;
+    for line in lines.iter().rev() {
+        if bottom <= 0 {
+            break;
+        }
+        let top = bottom - line_height - 2 * padding;
+        let baseline = (top + padding) as f32 + v_metrics.ascent;
+        let glyphs: Vec<_> = font
+            .layout(line, scale, point(padding as f32, baseline))
+            .collect();
+        let line_width = glyphs
+            .last()
+            .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+            .unwrap_or(0.0)
+            .ceil() as i32;
+
+        let mut put_pixel = |x: i32, y: i32, color: [u8; 4]| {
+            if x >= 0 && y >= 0 && x < width as i32 && y < height as i32 {
+                drawn.put_pixel(x as u32, y as u32, image::Rgba(color));
+            }
+        };
+        for y in top.max(0)..bottom {
+            for x in 0..line_width.saturating_add(padding).min(width as i32) {
+                put_pixel(x, y, [255, 255, 255, 255]);
+            }
+        }
+        for glyph in &glyphs {
+            if let Some(bounds) = glyph.pixel_bounding_box() {
+                if bounds.min.x >= width as i32 {
+                    break;
+                }
+                glyph.draw(|x, y, coverage| {
+                    // Anti-aliased edges would only turn into noise when
+                    // dithered, therefore the glyphs are drawn in solid black
+                    if coverage >= 0.5 {
+                        put_pixel(
+                            bounds.min.x + x as i32,
+                            bounds.min.y + y as i32,
+                            [0, 0, 0, 255],
+                        );
+                    }
+                });
+            }
+        }
+
+        bottom = top;
+    }
+
+    DynamicImage::ImageRgba8(drawn)
+}
+
+pub fn image_dithered(
+    image: &DynamicImage,
+    algorithm: DitheringAlgorithm,
+    palette: Palette,
+    metric: ColorMetric,
+    serpentine: bool,
+) -> DynamicImage {
+    let dithered = algorithm.apply(
         image.to_rgba().clone(),
-        dithering::jarvis_judice_ninke(),
-        dithering::palette_7_acep(),
+        palette.colors(),
+        metric,
+        serpentine,
     );
 
     DynamicImage::ImageRgba8(dithered)
 }
 
-#[inline(always)]
-fn is_odd(value: u32) -> bool {
-    value & 0x1 == 0x1
-}
-
-// Input must be dithered
-pub fn inkplate_raw(dithered_image: &DynamicImage) -> Vec<u8> {
+// Input must be dithered using the given palette
+pub fn inkplate_raw(dithered_image: &DynamicImage, palette: Palette, packing: Packing) -> Vec<u8> {
     let dithered = dithered_image.as_rgba8().unwrap();
+    let palette_colors = palette.colors();
+    let bits_per_pixel = packing.bits_per_pixel();
 
     let (width, height) = dithered.dimensions();
     // println!("dithered dimensions: {}x{}", width, height);
 
     // Minimize possible reallocations
-    let mut out_bytes: Vec<u8> = Vec::with_capacity((width * height / 2) as usize);
-
-    // We are encoding 2 3bit pixels per bit one in the upper byte, one in the
-    // lower. Furthermore we are always starting a new byte at the beginning of
-    // each row. Therefore the last one might need padding if the width is odd
-    let odd_width = is_odd(width);
+    let mut out_bytes: Vec<u8> = Vec::with_capacity(packing.row_bytes(width) * height as usize);
 
+    // Pixels are filled into each byte starting with the most significant
+    // bits. Every row starts with a new byte, therefore the last one of a row
+    // might need padding.
     for y in 0..height {
         let mut current_byte: u8 = 0x0;
+        let mut filled_bits = 0;
         for x in 0..width {
             let color_pixel = dithering::get_pixel(&dithered, x, y);
-            // We are mapping to this palette:
-            // NAME            INDEX      COLOR
-            // ---             ---        ---
-            // INKPLATE_BLACK  0b00000000 0x000000
-            // INKPLATE_WHITE  0b00000001 0xffffff
-            // INKPLATE_GREEN  0b00000010 0x00ff00
-            // INKPLATE_BLUE   0b00000011 0x0000ff
-            // INKPLATE_RED    0b00000100 0xff0000
-            // INKPLATE_YELLOW 0b00000101 0xffff00
-            // INKPLATE_ORANGE 0b00000110 0xff8000
-            let indexed_pixel = match color_pixel {
-                0x000000 => 0u8,
-                0xffffff => 1u8,
-                0x00ff00 => 2u8,
-                0x0000ff => 3u8,
-                0xff0000 => 4u8,
-                0xffff00 => 5u8,
-                0xff8000 => 6u8,
-                _ => panic!(
+            // The position within the palette is the index used by the display.
+            // For the AcEP palettes we are therefore mapping to:
+            // NAME            INDEX      COLOR    CALIBRATED
+            // ---             ---        ---      ---
+            // INKPLATE_BLACK  0b00000000 0x000000 0x393039
+            // INKPLATE_WHITE  0b00000001 0xffffff 0xffffff
+            // INKPLATE_GREEN  0b00000010 0x00ff00 0x3a5b46
+            // INKPLATE_BLUE   0b00000011 0x0000ff 0x3d3b5e
+            // INKPLATE_RED    0b00000100 0xff0000 0x9c484b
+            // INKPLATE_YELLOW 0b00000101 0xffff00 0xd0be47
+            // INKPLATE_ORANGE 0b00000110 0xff8000 0xb16a49
+            let indexed_pixel = match palette_colors.iter().position(|c| *c == color_pixel) {
+                Some(index) => index as u8,
+                None => panic!(
                     "Could not match dithered color {:x} to inkplate index",
                     color_pixel
                 ),
             };
 
-            if is_odd(x) {
-                // First of two pixels (high nible)
-                current_byte = ((indexed_pixel << 1) << 4) & 0xf0
-            } else {
-                // Second of two pixels (low nible)
-                current_byte = current_byte | (indexed_pixel << 1);
+            filled_bits += bits_per_pixel;
+            current_byte |= packing.value(indexed_pixel) << (8 - filled_bits);
 
-                // Write finished byte
-                out_bytes.push(current_byte);
-            }
-
-            if odd_width && x == width - 1 {
-                // Write out last byte with padding before switching lines.
+            if filled_bits == 8 || x == width - 1 {
+                // Write finished or last byte of the row
                 out_bytes.push(current_byte);
+                current_byte = 0x0;
+                filled_bits = 0;
             }
         }
     }
 
     out_bytes
 }
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    /// Image of the palette colors at the given indices
+    fn indexed_image(palette: Palette, rows: &[&[u8]]) -> DynamicImage {
+        let colors = palette.colors();
+        let image = RgbaImage::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| {
+            let color = colors[rows[y as usize][x as usize] as usize];
+            Rgba([(color >> 16) as u8, (color >> 8) as u8, color as u8, 255])
+        });
+        DynamicImage::ImageRgba8(image)
+    }
+
+    /// Font with a single glyph for `I`, a square filling 0.1 to 0.9 em
+    /// horizontally and the baseline up to 0.7 em. Ascent is 0.8 em, descent
+    /// 0.2 em.
+    fn block_font() -> Font<'static> {
+        Font::try_from_bytes(include_bytes!("../testdata/block.ttf")).unwrap()
+    }
+
+    const GRAY: [u8; 4] = [128, 128, 128, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+
+    fn draw(lines: &[&str]) -> RgbaImage {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(100, 60, Rgba(GRAY)));
+        let lines: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+        // 20 pixels per line plus a padding of 5 pixels above and below
+        draw_text_lines(&image, &lines, &block_font(), 20).to_rgba()
+    }
+
+    #[test]
+    fn draws_text_lines_onto_bottom_left_corner() {
+        let drawn = draw(&["I", "II"]);
+
+        // Bottom line from y = 30, with glyphs from x = 7 to 23 and 27 to 43
+        // above the baseline at y = 51
+        assert_eq!(drawn.get_pixel(1, 31).data, WHITE);
+        assert_eq!(drawn.get_pixel(10, 45).data, BLACK);
+        assert_eq!(drawn.get_pixel(25, 45).data, WHITE);
+        assert_eq!(drawn.get_pixel(35, 45).data, BLACK);
+        assert_eq!(drawn.get_pixel(45, 45).data, WHITE);
+        assert_eq!(drawn.get_pixel(60, 45).data, GRAY);
+        // Line above, which is narrower
+        assert_eq!(drawn.get_pixel(10, 15).data, BLACK);
+        assert_eq!(drawn.get_pixel(35, 15).data, GRAY);
+    }
+
+    #[test]
+    fn cuts_off_text_exceeding_the_image() {
+        let line = "I".repeat(1000);
+        let drawn = draw(&[&line, &line, &line, &line]);
+        assert_eq!(drawn.get_pixel(99, 0).data, WHITE);
+        assert_eq!(drawn.get_pixel(90, 45).data, BLACK);
+    }
+
+    #[test]
+    fn packs_shifted_nibbles_high_nibble_first() {
+        let image = indexed_image(Palette::Acep, &[&[1, 2, 6], &[0, 5, 4]]);
+        assert_eq!(
+            inkplate_raw(&image, Palette::Acep, Packing::ShiftedNibbles),
+            vec![0x24, 0xc0, 0x0a, 0x80]
+        );
+    }
+
+    #[test]
+    fn packs_nibbles() {
+        let image = indexed_image(Palette::Grayscale, &[&[7, 1, 3], &[0, 6, 2]]);
+        assert_eq!(
+            inkplate_raw(&image, Palette::Grayscale, Packing::Nibbles),
+            vec![0x71, 0x30, 0x06, 0x20]
+        );
+    }
+
+    #[test]
+    fn packs_two_bits() {
+        let image = indexed_image(
+            Palette::BlackWhiteRed,
+            &[&[1, 2, 0, 1, 2], &[2, 2, 2, 2, 0]],
+        );
+        assert_eq!(
+            inkplate_raw(&image, Palette::BlackWhiteRed, Packing::TwoBits),
+            vec![0x61, 0x80, 0xaa, 0x00]
+        );
+    }
+
+    #[test]
+    fn packs_bits() {
+        let image = indexed_image(
+            Palette::Monochrome,
+            &[&[1, 0, 1, 1, 0, 0, 0, 1, 1, 1], &[0; 10]],
+        );
+        assert_eq!(
+            inkplate_raw(&image, Palette::Monochrome, Packing::Bits),
+            vec![0xb1, 0xc0, 0x00, 0x00]
+        );
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use anyhow::anyhow;
+use rand::seq::SliceRandom;
+use rusqlite::Connection;
+
+use crate::content_filter::{ContentFilter, FilteredImageSource};
+use crate::directory::DirectoryImageSource;
+use crate::lexica::{fetch_lexica, LazyLexicaImage, SearchTerms};
+use crate::posterity::{
+    backlog_images, count_backlog_images, load_lexica_cursor, store_image_and_prompt,
+    store_lexica_cursor, PosterityImageSource,
+};
+use crate::{AppConfig, ImageRequest};
+
+/// Provider of candidate images, which may be shown on the display.
+///
+/// Candidates are loaded lazily. A source therefore only needs to provide the
+/// metadata (id, prompt, url and raw document) upfront, while the image itself
+/// is only retrieved for the candidate, which is finally selected.
+pub trait ImageSource: Send + Sync {
+    /// Name used to select the source via configuration
+    fn name(&self) -> &'static str;
+
+    fn fetch_candidates(
+        &self,
+        connection: &Connection,
+        request: &ImageRequest,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>>;
+
+    /// Fetch candidates, which are passed through the given filter. Sources
+    /// setting aside some of their candidates override this, to only set
+    /// aside accepted ones.
+    fn fetch_filtered_candidates(
+        &self,
+        connection: &Connection,
+        request: &ImageRequest,
+        filter: &dyn Fn(Vec<LazyLexicaImage>) -> anyhow::Result<Vec<LazyLexicaImage>>,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>> {
+        filter(self.fetch_candidates(connection, request)?)
+    }
+}
+
+/// Number of backlog images offered as candidates at once
+const BACKLOG_CANDIDATES: usize = 10;
+
+/// Random images scraped from the lexica.art infinite-prompts api
+///
+/// The search is taken from the request or selected from the configured search
+/// terms. Each query fetches the next page of results for the search, until
+/// `max_pages` have been fetched and it starts over with the first one.
+///
+/// Each scrape provides dozens of images, of which only one is shown. Up to
+/// `backlog_size` of them are therefore set aside and stored in the posterity
+/// database in the background. Those are served, before lexica.art is
+/// contacted again. Only candidates accepted by the filter of the wrapping
+/// `FilteredImageSource` are set aside, so rejected images are never stored.
+pub struct LexicaImageSource {
+    db_file: String,
+    backlog_size: usize,
+    search_terms: SearchTerms,
+    max_pages: u32,
+}
+
+impl ImageSource for LexicaImageSource {
+    fn name(&self) -> &'static str {
+        "lexica"
+    }
+
+    fn fetch_candidates(
+        &self,
+        connection: &Connection,
+        request: &ImageRequest,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>> {
+        self.fetch_filtered_candidates(connection, request, &Ok)
+    }
+
+    fn fetch_filtered_candidates(
+        &self,
+        connection: &Connection,
+        request: &ImageRequest,
+        filter: &dyn Fn(Vec<LazyLexicaImage>) -> anyhow::Result<Vec<LazyLexicaImage>>,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>> {
+        let search = match &request.search {
+            Some(search) => search.clone(),
+            None => self.search_terms.select(SystemTime::now()),
+        };
+
+        let backlog = filter(backlog_images(connection, &search, BACKLOG_CANDIDATES)?)?;
+        if !backlog.is_empty() {
+            return Ok(backlog);
+        }
+
+        let (cursor, page) = load_lexica_cursor(connection, &search)?;
+        let lexica_page = fetch_lexica(&search, cursor)?;
+        match lexica_page.next_cursor {
+            Some(next_cursor) if page + 1 < self.max_pages => {
+                store_lexica_cursor(connection, &search, next_cursor, page + 1)?
+            }
+            // Wrap around, once the configured depth or the last page is reached
+            _ => store_lexica_cursor(connection, &search, 0, 0)?,
+        }
+
+        let mut candidates = filter(lexica_page.images)?;
+        candidates.shuffle(&mut rand::thread_rng());
+
+        // Always leave at least one candidate to be shown right now
+        let missing = self
+            .backlog_size
+            .saturating_sub(count_backlog_images(connection, &search)?)
+            .min(candidates.len().saturating_sub(1));
+        let backlog = candidates.split_off(candidates.len() - missing);
+        if !backlog.is_empty() {
+            let db_file = self.db_file.clone();
+            thread::spawn(move || store_backlog(&db_file, backlog, &search));
+        }
+
+        Ok(candidates)
+    }
+}
+
+/// Download and store the images set aside for later. Each of them needs to
+/// be fetched and encoded, therefore this is not done while a request waits.
+fn store_backlog(db_file: &str, backlog: Vec<LazyLexicaImage>, search: &str) {
+    let connection = match Connection::open(db_file) {
+        Ok(connection) => connection,
+        Err(error) => {
+            println!("Could not open database to store the backlog: {:?}", error);
+            return;
+        }
+    };
+
+    for lexica_image in backlog {
+        if let Err(error) = store_image_and_prompt(&connection, &lexica_image, search) {
+            println!("Could not store image {}: {:?}", lexica_image.id, error);
+        }
+    }
+}
+
+/// Asks each of the given sources in order, until one of them provides
+/// candidates. Failing sources are logged and skipped.
+pub struct FallbackImageSource {
+    sources: Vec<Box<dyn ImageSource>>,
+}
+
+impl ImageSource for FallbackImageSource {
+    fn name(&self) -> &'static str {
+        "fallback"
+    }
+
+    fn fetch_candidates(
+        &self,
+        connection: &Connection,
+        request: &ImageRequest,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>> {
+        for source in &self.sources {
+            match source.fetch_candidates(connection, request) {
+                Ok(candidates) if !candidates.is_empty() => return Ok(candidates),
+                Ok(_) => println!("Image source {} provided no candidates", source.name()),
+                Err(error) => println!("Image source {} failed: {:?}", source.name(), error),
+            }
+        }
+
+        Err(anyhow!("None of the image sources provided any candidates"))
+    }
+}
+
+pub fn image_source_by_name(
+    name: &str,
+    config: &AppConfig,
+) -> anyhow::Result<Box<dyn ImageSource>> {
+    match name {
+        "lexica" => Ok(Box::new(LexicaImageSource {
+            db_file: config.db_file(),
+            backlog_size: config.lexica_backlog_size,
+            search_terms: SearchTerms::parse(
+                config.lexica_search.as_deref().unwrap_or_default(),
+                config
+                    .lexica_search_rotation
+                    .map(|minutes| Duration::from_secs(minutes * 60)),
+            )?,
+            max_pages: config.lexica_max_pages,
+        })),
+        "posterity" => Ok(Box::new(PosterityImageSource)),
+        "directory" => match &config.image_directory {
+            Some(directory) => Ok(Box::new(DirectoryImageSource::new(PathBuf::from(
+                directory,
+            )))),
+            None => Err(anyhow!(
+                "The directory image source requires an image_directory to be configured"
+            )),
+        },
+        _ => Err(anyhow!("Unknown image source: {}", name)),
+    }
+}
+
+/// Create the image source given by the comma separated list of source names.
+/// If more than one source is given they are used as fallbacks for each other
+/// in the given order. The candidates of every source are passed through the
+/// configured content filter.
+pub fn image_source_from_names(
+    names: &str,
+    config: &AppConfig,
+) -> anyhow::Result<Box<dyn ImageSource>> {
+    let content_filter = Arc::new(ContentFilter::parse(
+        config.content_blocklist.as_deref().unwrap_or_default(),
+        config.allow_nsfw,
+    )?);
+
+    let mut sources = names
+        .split(',')
+        .map(|name| {
+            let source = image_source_by_name(name.trim(), config)?;
+            Ok(Box::new(FilteredImageSource::new(
+                source,
+                Arc::clone(&content_filter),
+            )) as Box<dyn ImageSource>)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    if sources.len() == 1 {
+        return Ok(sources.remove(0));
+    }
+
+    Ok(Box::new(FallbackImageSource { sources }))
+}
+
+/// Uses the image source selected by a request, e.g. by the settings of a
+/// device, or the configured one otherwise. Selected sources are created once
+/// they are first requested.
+pub struct SelectableImageSource {
+    config: Arc<AppConfig>,
+    configured: Box<dyn ImageSource>,
+    selected: Mutex<HashMap<String, Arc<dyn ImageSource>>>,
+}
+
+impl SelectableImageSource {
+    pub fn new(config: Arc<AppConfig>) -> anyhow::Result<Self> {
+        Ok(Self {
+            configured: image_source_from_names(&config.image_source, &config)?,
+            config,
+            selected: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn selected(&self, names: &str) -> anyhow::Result<Arc<dyn ImageSource>> {
+        let mut selected = self.selected.lock().unwrap();
+        if let Some(source) = selected.get(names) {
+            return Ok(Arc::clone(source));
+        }
+
+        let source: Arc<dyn ImageSource> = Arc::from(image_source_from_names(names, &self.config)?);
+        selected.insert(names.to_string(), Arc::clone(&source));
+        Ok(source)
+    }
+}
+
+impl ImageSource for SelectableImageSource {
+    fn name(&self) -> &'static str {
+        "selectable"
+    }
+
+    fn fetch_candidates(
+        &self,
+        connection: &Connection,
+        request: &ImageRequest,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>> {
+        match &request.image_source {
+            Some(names) => self.selected(names)?.fetch_candidates(connection, request),
+            None => self.configured.fetch_candidates(connection, request),
+        }
+    }
+}
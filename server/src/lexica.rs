@@ -1,8 +1,11 @@
 use std::cell::RefCell;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
+use anyhow::anyhow;
 use curl::easy::Easy;
-use serde_json::Value;
+use rand::distributions::{Distribution, WeightedIndex};
+use serde_json::{json, Value};
 
 use crate::my_curl::{get_with_curl, post_with_curl};
 
@@ -12,24 +15,39 @@ fn fetch_image(easy: &mut Easy, url: &str) -> anyhow::Result<image::DynamicImage
     Ok(image::load_from_memory(&image_data)?)
 }
 
-fn fetch_prompt_images(
-    _easy: &mut Easy,
-    prompt_json: &str,
-) -> anyhow::Result<Vec<LazyLexicaImage>> {
+/// One page of results of the infinite-prompts api
+pub struct LexicaPage {
+    pub images: Vec<LazyLexicaImage>,
+    /// Cursor to request the following page with, if there is one
+    pub next_cursor: Option<u64>,
+}
+
+fn fetch_prompt_images(_easy: &mut Easy, prompt_json: &str) -> anyhow::Result<LexicaPage> {
     // let start = Instant::now();
     let v: serde_json::Value = serde_json::from_str(prompt_json)?;
-    let prompts = &v["prompts"];
+    let prompts = v["prompts"].as_array().ok_or_else(|| {
+        anyhow!(
+            "Unexpected lexica response without prompts: {:.200}",
+            prompt_json
+        )
+    })?;
 
     let mut lazy_images = Vec::new();
-    for prompt in prompts.as_array().unwrap() {
-        for image in prompt["images"].as_array().unwrap() {
+    for prompt in prompts {
+        let images = prompt["images"]
+            .as_array()
+            .ok_or_else(|| anyhow!("Unexpected lexica prompt without images: {}", prompt))?;
+        for image in images {
+            let id = image["id"]
+                .as_str()
+                .ok_or_else(|| anyhow!("Unexpected lexica image without id: {}", image))?;
             let image_url = format!(
                 "https://lexica-serve-encoded-images2.sharif.workers.dev/md2/{}",
                 //"https://image.lexica.art/md/{}",
-                image["id"].as_str().unwrap(),
+                id,
             );
             lazy_images.push(LazyLexicaImage::new(
-                image["id"].as_str().unwrap().to_string(),
+                id.to_string(),
                 image_url,
                 prompt.to_owned(),
                 image.to_owned(),
@@ -46,10 +64,13 @@ fn fetch_prompt_images(
     // let end = Instant::now();
     // println!("fetch_prompt_images run duration: {:?}", end - start);
 
-    Ok(lazy_images)
+    Ok(LexicaPage {
+        images: lazy_images,
+        next_cursor: v["nextCursor"].as_u64(),
+    })
 }
 
-pub fn fetch_lexica() -> anyhow::Result<Vec<LazyLexicaImage>> {
+pub fn fetch_lexica(search: &str, cursor: u64) -> anyhow::Result<LexicaPage> {
     // let start = Instant::now();
 
     // Tried this with request. However then it is detected as "non browser" and
@@ -71,7 +92,13 @@ pub fn fetch_lexica() -> anyhow::Result<Vec<LazyLexicaImage>> {
     let infinity_prompts_json = post_with_curl(
         &mut easy,
         "https://lexica.art/api/infinite-prompts",
-        "{\"text\":\"\",\"searchMode\":\"images\",\"source\":\"search\",\"cursor\":0}",
+        &json!({
+            "text": search,
+            "searchMode": "images",
+            "source": "search",
+            "cursor": cursor
+        })
+        .to_string(),
     )?;
 
     // let end = Instant::now();
@@ -81,21 +108,112 @@ pub fn fetch_lexica() -> anyhow::Result<Vec<LazyLexicaImage>> {
     Ok(result)
 }
 
+/// Search terms used to query lexica.art, each with an associated weight.
+///
+/// Terms are either chosen randomly according to their weight, or rotated on a
+/// fixed schedule, where the weight is the number of consecutive periods a term
+/// is used for.
+#[derive(Debug, Clone, Default)]
+pub struct SearchTerms {
+    terms: Vec<(String, u32)>,
+    rotation: Option<Duration>,
+}
+
+impl SearchTerms {
+    /// Parse a list of search terms separated by `;`. Each term may be followed
+    /// by `:<weight>`, e.g. `watercolor landscape:3; cyberpunk city`.
+    pub fn parse(definition: &str, rotation: Option<Duration>) -> anyhow::Result<Self> {
+        let mut terms = Vec::new();
+        for entry in definition
+            .split(';')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            // Colons within a term are kept, unless followed by a number
+            let (term, weight) = match entry
+                .rsplit_once(':')
+                .and_then(|(term, weight)| Some((term.trim(), weight.trim().parse().ok()?)))
+            {
+                Some((term, weight)) => (term, weight),
+                None => (entry, 1),
+            };
+            if weight > 0 {
+                terms.push((term.to_string(), weight));
+            }
+        }
+
+        Ok(Self { terms, rotation })
+    }
+
+    /// Search term to use for the next query. An empty search is used if no
+    /// terms are configured.
+    pub fn select(&self, now: SystemTime) -> String {
+        if self.terms.is_empty() {
+            return String::new();
+        }
+
+        let index = match self.rotation {
+            Some(rotation) => {
+                let since_epoch = now.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+                let total_weight: u64 = self.terms.iter().map(|(_, w)| *w as u64).sum();
+                let mut period = (since_epoch.as_secs() / rotation.as_secs().max(1)) % total_weight;
+                let mut index = 0;
+                while period >= self.terms[index].1 as u64 {
+                    period -= self.terms[index].1 as u64;
+                    index += 1;
+                }
+                index
+            }
+            None => WeightedIndex::new(self.terms.iter().map(|(_, w)| *w))
+                .unwrap()
+                .sample(&mut rand::thread_rng()),
+        };
+
+        self.terms[index].0.clone()
+    }
+}
+
+pub type ImageLoader = Box<dyn Fn() -> anyhow::Result<image::DynamicImage> + Send>;
+
 pub struct LazyLexicaImage {
     pub id: String,
     pub url: String,
     pub prompt: Value,
     pub metadata: Value,
+    loader: ImageLoader,
     image: RefCell<Option<Arc<image::DynamicImage>>>,
 }
 
 impl LazyLexicaImage {
     pub fn new(id: String, url: String, prompt: Value, metadata: Value) -> Self {
+        let image_url = url.clone();
+        Self::with_loader(
+            id,
+            url,
+            prompt,
+            metadata,
+            Box::new(move || {
+                let mut easy = curl::easy::Easy::new();
+                fetch_image(&mut easy, &image_url)
+            }),
+        )
+    }
+
+    /// Create an image, which is not fetched from its url, but retrieved
+    /// using the given loader, once it is needed.
+    pub fn with_loader(
+        id: String,
+        url: String,
+        prompt: Value,
+        metadata: Value,
+        loader: ImageLoader,
+    ) -> Self {
         Self {
             id,
             url,
             prompt,
             metadata,
+            loader,
             image: RefCell::new(None),
         }
     }
@@ -107,8 +225,7 @@ impl LazyLexicaImage {
             None => {
                 // let start = Instant::now();
                 // dbg!(&self.url);
-                let mut easy = curl::easy::Easy::new();
-                let fetched_image = Arc::new(fetch_image(&mut easy, &self.url)?);
+                let fetched_image = Arc::new((self.loader)()?);
                 *mut_image = Some(Arc::clone(&fetched_image));
 
                 // let end = Instant::now();
@@ -119,3 +236,79 @@ impl LazyLexicaImage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(search_terms: &SearchTerms) -> Vec<(&str, u32)> {
+        search_terms
+            .terms
+            .iter()
+            .map(|(term, weight)| (term.as_str(), *weight))
+            .collect()
+    }
+
+    #[test]
+    fn parses_prompt_images() {
+        let page = fetch_prompt_images(
+            &mut Easy::new(),
+            r#"{"prompts": [{"id": "p", "images": [{"id": "a"}, {"id": "b"}]}], "nextCursor": 50}"#,
+        )
+        .unwrap();
+        let ids: Vec<&str> = page.images.iter().map(|image| image.id.as_str()).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        assert_eq!(page.next_cursor, Some(50));
+    }
+
+    #[test]
+    fn rejects_unexpected_responses() {
+        for body in [
+            r#"{"error": "Too many requests"}"#,
+            r#"{"prompts": [{"id": "p"}]}"#,
+            r#"{"prompts": [{"id": "p", "images": [{"width": 512}]}]}"#,
+            "<html>Just a moment...</html>",
+        ] {
+            assert!(fetch_prompt_images(&mut Easy::new(), body).is_err());
+        }
+    }
+
+    #[test]
+    fn parses_weighted_terms() {
+        let search_terms =
+            SearchTerms::parse(" watercolor landscape:3; cyberpunk city ;; ignored:0", None)
+                .unwrap();
+        assert_eq!(
+            terms(&search_terms),
+            vec![("watercolor landscape", 3), ("cyberpunk city", 1)]
+        );
+    }
+
+    #[test]
+    fn keeps_colons_within_terms() {
+        let search_terms = SearchTerms::parse("style: watercolor; photo: 35mm:2", None).unwrap();
+        assert_eq!(
+            terms(&search_terms),
+            vec![("style: watercolor", 1), ("photo: 35mm", 2)]
+        );
+    }
+
+    #[test]
+    fn selects_nothing_without_terms() {
+        let search_terms = SearchTerms::parse("", None).unwrap();
+        assert_eq!(search_terms.select(SystemTime::now()), "");
+    }
+
+    #[test]
+    fn rotates_terms_by_weight() {
+        let rotation = Duration::from_secs(60);
+        let search_terms = SearchTerms::parse("first:2; second", Some(rotation)).unwrap();
+        let selected: Vec<String> = (0..6)
+            .map(|period| search_terms.select(SystemTime::UNIX_EPOCH + rotation * period))
+            .collect();
+        assert_eq!(
+            selected,
+            vec!["first", "first", "second", "first", "first", "second"]
+        );
+    }
+}
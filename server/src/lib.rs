@@ -0,0 +1,2 @@
+//! Image processing shared by the server and its benchmarks
+pub mod dithering;
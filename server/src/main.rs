@@ -1,34 +1,243 @@
-mod dithering;
+mod adjustments;
+mod compression;
+mod content_filter;
+mod devices;
+mod directory;
+mod display;
+mod frames;
 mod image_data;
+mod image_source;
 mod lexica;
 mod my_curl;
+mod overlay;
 mod posterity;
+mod prefetch;
+mod schedule;
+mod telemetry;
 
+use std::fs;
 use std::ops::Deref;
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 
 use figment::providers::Env;
 use figment::Figment;
 
-use lexica::{fetch_lexica, LazyLexicaImage};
-use posterity::{create_posterity_db, give_image_to_posterity, store_image_and_prompt};
+use adjustments::Adjustments;
+use compression::Compression;
+use devices::{
+    device_history, list_devices, load_device, register_device, remove_device, update_device,
+    Device, DeviceUpdate, ShownImage,
+};
+use display::{DisplayProfile, Orientation};
+use frames::{CurrentFrames, DEFAULT_DEVICE};
+use image::DynamicImage;
+use image_source::{image_source_from_names, ImageSource, SelectableImageSource};
+use lexica::LazyLexicaImage;
+use lexica_inkplate_server::dithering::{self, ColorMetric, DitheringAlgorithm, Palette};
+use overlay::Overlays;
+use posterity::{create_posterity_db, give_prepared_image_to_posterity};
+use prefetch::{spawn_prefetch_worker, PrefetchQueue};
 use rand::Rng;
-use rocket::http::{ContentType, Status};
+use rocket::http::{ContentType, Header, Status};
 use rocket::request::{FromRequest, Outcome};
 use rocket::serde::json::Json;
-use rocket::{Request, State};
+use rocket::{FromForm, Request, Responder, State};
 use rusqlite::Connection;
+use rusttype::Font;
+use schedule::Schedule;
 use serde::{Deserialize, Serialize};
+use telemetry::{
+    battery_status, is_battery_low, record_telemetry, telemetry_history, BatteryStatus, Telemetry,
+    TelemetryReport,
+};
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 struct AppConfig {
     storage_path: String,
+    #[serde(default = "default_image_source")]
+    image_source: String,
+    image_directory: Option<String>,
+    #[serde(default = "default_prefetch_count")]
+    prefetch_count: usize,
+    #[serde(default = "default_lexica_backlog_size")]
+    lexica_backlog_size: usize,
+    lexica_search: Option<String>,
+    lexica_search_rotation: Option<u64>,
+    #[serde(default = "default_lexica_max_pages")]
+    lexica_max_pages: u32,
+    content_blocklist: Option<String>,
+    #[serde(default)]
+    allow_nsfw: bool,
+    #[serde(default = "default_battery_warning_voltage")]
+    battery_warning_voltage: f64,
+    overlay_font: Option<String>,
+}
+
+impl AppConfig {
+    /// Path of the posterity database
+    fn db_file(&self) -> String {
+        format!("{}/posterity.sqlite", self.storage_path)
+    }
+}
+
+fn default_image_source() -> String {
+    "lexica,posterity".to_string()
+}
+
+fn default_prefetch_count() -> usize {
+    2
+}
+
+fn default_lexica_backlog_size() -> usize {
+    10
+}
+
+fn default_lexica_max_pages() -> u32 {
+    20
+}
+
+fn default_battery_warning_voltage() -> f64 {
+    3.6
+}
+
+fn default_night_start() -> String {
+    "22:00".to_string()
+}
+
+fn default_night_end() -> String {
+    "07:00".to_string()
+}
+
+fn default_time_zone() -> String {
+    "UTC".to_string()
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 struct PersistedConfig {
     update_at_night: bool,
     update_interval: usize,
+    #[serde(default = "default_night_start")]
+    night_start: String,
+    #[serde(default = "default_night_end")]
+    night_end: String,
+    #[serde(default = "default_time_zone")]
+    time_zone: String,
+    #[serde(default)]
+    display: DisplayProfile,
+    #[serde(default)]
+    dithering: DitheringAlgorithm,
+    #[serde(default)]
+    palette: Palette,
+    #[serde(default)]
+    color_metric: ColorMetric,
+    #[serde(default)]
+    serpentine: bool,
+    #[serde(default)]
+    adjustments: Adjustments,
+    #[serde(default)]
+    overlays: Overlays,
+}
+
+impl Default for PersistedConfig {
+    fn default() -> Self {
+        Self {
+            update_at_night: false,
+            update_interval: 15,
+            night_start: default_night_start(),
+            night_end: default_night_end(),
+            time_zone: default_time_zone(),
+            display: DisplayProfile::default(),
+            dithering: DitheringAlgorithm::default(),
+            palette: Palette::default(),
+            color_metric: ColorMetric::default(),
+            serpentine: false,
+            adjustments: Adjustments::default(),
+            overlays: Overlays::default(),
+        }
+    }
+}
+
+impl PersistedConfig {
+    /// Load the config stored at the given path, or the defaults if nothing
+    /// has been stored yet.
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    fn store(&self, path: &Path) -> anyhow::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    fn schedule(&self) -> anyhow::Result<Schedule> {
+        Schedule::new(
+            self.update_at_night,
+            self.update_interval,
+            &self.night_start,
+            &self.night_end,
+            &self.time_zone,
+        )
+    }
+
+    /// Check the config for values, which would prevent images from being
+    /// served
+    fn validate(&self) -> anyhow::Result<()> {
+        self.display.check_palette(self.palette)?;
+        self.adjustments.validate()?;
+        self.overlays.validate()?;
+        self.schedule()?;
+        Ok(())
+    }
+
+    /// Request used, if no parameters are given by the client
+    fn default_request(&self) -> ImageRequest {
+        ImageRequest {
+            device: None,
+            image_source: None,
+            search: None,
+            display: self.display,
+            dithering: self.dithering,
+            palette: self.palette,
+            color_metric: self.color_metric,
+            serpentine: self.serpentine,
+            adjustments: self.adjustments,
+            overlays: self.overlays.clone(),
+            battery_low: false,
+            time_zone: self.time_zone.parse().unwrap_or(Tz::UTC),
+        }
+    }
+}
+
+struct ConfigFile(pub PathBuf);
+
+/// Font the overlays are rendered with. Overlays are not shown without one.
+pub struct OverlayFont(Option<Font<'static>>);
+
+impl OverlayFont {
+    /// The image with the overlays of the request drawn onto it, showing the
+    /// current time as the time of the last update
+    fn draw(&self, image: DynamicImage, request: &ImageRequest) -> DynamicImage {
+        let font = match &self.0 {
+            Some(font) => font,
+            None => return image,
+        };
+        let updated_at = Utc::now().with_timezone(&request.time_zone);
+        let lines = request.overlays.lines(request.battery_low, updated_at);
+        if lines.is_empty() {
+            return image;
+        }
+        image_data::draw_text_lines(&image, &lines, font, request.overlays.font_size)
+    }
 }
 
 struct DbFile(pub String);
@@ -69,80 +278,455 @@ impl<'r> FromRequest<'r> for DbConn {
 #[derive(Clone)]
 pub struct ProcessedImage {
     pub cropped: Vec<u8>,
+    pub adjusted: Vec<u8>,
     pub dithered: Vec<u8>,
     pub rotated: Vec<u8>,
     pub inkplate: Vec<u8>,
 }
 
-fn process_lazy_lexica_image(lexica_image: &LazyLexicaImage) -> ProcessedImage {
-    let cropped = image_data::scale_and_crop_image(&lexica_image.image().unwrap());
-    let dithered = image_data::image_dithered(&cropped);
-    let rotated = image_data::rotate_image(&dithered);
-    let inkplate = image_data::inkplate_raw(&rotated);
+fn process_lazy_lexica_image(
+    lexica_image: &LazyLexicaImage,
+    overlay_font: &OverlayFont,
+    request: &ImageRequest,
+) -> anyhow::Result<ProcessedImage> {
+    let image = lexica_image.image()?;
+    let (width, height) = request.display.image_dimensions();
+    let cropped = image_data::scale_and_crop_image(&image, width, height);
+    let adjusted = request.adjustments.apply(&cropped);
+    let adjusted = overlay_font.draw(adjusted, request);
+    let dithered = image_data::image_dithered(
+        &adjusted,
+        request.dithering,
+        request.palette,
+        request.color_metric,
+        request.serpentine,
+    );
+    let rotated = match request.display.orientation() {
+        Orientation::Landscape => dithered.clone(),
+        Orientation::Portrait => image_data::rotate_image(&dithered),
+    };
+    let inkplate = image_data::inkplate_raw(&rotated, request.palette, request.display.packing());
 
-    ProcessedImage {
+    Ok(ProcessedImage {
         cropped: image_data::png(&cropped),
+        adjusted: image_data::png(&adjusted),
         dithered: image_data::png(&dithered),
         rotated: image_data::png(&rotated),
         inkplate,
+    })
+}
+
+/// Header used to request a compressed frame. The compression actually used
+/// is returned in the same header.
+const COMPRESSION_HEADER: &str = "X-Inkplate-Compression";
+
+/// The compression is requested using either the `compression` query
+/// parameter, or the compression header.
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Compression {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Compression, Self::Error> {
+        let name = request
+            .query_value::<&str>("compression")
+            .and_then(Result::ok)
+            .or_else(|| request.headers().get_one(COMPRESSION_HEADER));
+
+        match name.map(str::parse).transpose() {
+            Ok(compression) => Outcome::Success(compression.unwrap_or_default()),
+            Err(error) => {
+                println!("Invalid request: {:?}", error);
+                Outcome::Failure((Status::BadRequest, ()))
+            }
+        }
     }
 }
 
-#[rocket::get("/lexica/png/cropped")]
-async fn lexica_png_original(connection: DbConn) -> Option<(ContentType, Vec<u8>)> {
-    let lexica = fetch_lexica().unwrap();
-    let mut rng = rand::thread_rng();
-    let image_index = rng.gen_range(0..lexica.len());
-    let processed_image = process_lazy_lexica_image(&lexica[image_index]);
-    {
-        let processed_image = processed_image.clone();
-        tokio::spawn(async move {
-            store_image_and_prompt(&connection, &lexica[0]);
-            give_image_to_posterity(&connection, &lexica[0], &processed_image);
-            // Fetch other listed images for later ;)
-            // TODO: Implement loading from those images, as well as using it in
-            // all retrieval functions.
-            // SELECT COUNT(l.id) from lexica_image as l WHERE NOT EXISTS (SELECT p.id from posterity p WHERE p.lexica_image = l.id);
-            // for image in &lexica[1..11] {
-            //     store_image_and_prompt(&connection, image);
-            // }
-        });
+/// Number of images returned by the history of a device, unless requested
+/// otherwise
+const DEVICE_HISTORY_LIMIT: usize = 20;
+
+/// Number of reports returned by the telemetry of a device, unless requested
+/// otherwise
+const TELEMETRY_LIMIT: usize = 1000;
+
+/// Header telling the device how many seconds to sleep until its next update
+const SLEEP_HEADER: &str = "X-Inkplate-Sleep";
+
+/// Header asking the device to draw the battery warning itself, as it could
+/// not be rendered onto the frame
+const BATTERY_WARNING_HEADER: &str = "X-Inkplate-Battery-Warning";
+
+/// Header identifying the device by a token or its MAC address
+const DEVICE_HEADER: &str = "X-Inkplate-Device";
+
+/// The requesting device, identified by either the `device` query parameter,
+/// or the device header
+struct DeviceId<'r>(&'r str);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for DeviceId<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<DeviceId<'r>, Self::Error> {
+        let id = request
+            .query_value::<&str>("device")
+            .and_then(Result::ok)
+            .or_else(|| request.headers().get_one(DEVICE_HEADER))
+            .unwrap_or(DEFAULT_DEVICE);
+        Outcome::Success(DeviceId(id))
     }
-    return Some((ContentType::PNG, processed_image.cropped));
 }
 
-#[rocket::get("/lexica/png/dithered")]
-async fn lexica_png_dithered(connection: DbConn) -> Option<(ContentType, Vec<u8>)> {
-    let lexica = fetch_lexica().unwrap();
-    let mut rng = rand::thread_rng();
-    let image_index = rng.gen_range(0..lexica.len());
-    let processed_image = process_lazy_lexica_image(&lexica[image_index]);
-    {
-        let processed_image = processed_image.clone();
-        tokio::spawn(async move {
-            store_image_and_prompt(&connection, &lexica[0]);
-            give_image_to_posterity(&connection, &lexica[0], &processed_image);
-        });
+/// Value of the `If-None-Match` header, if one has been given
+struct IfNoneMatch<'r>(Option<&'r str>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<IfNoneMatch<'r>, Self::Error> {
+        Outcome::Success(IfNoneMatch(request.headers().get_one("If-None-Match")))
+    }
+}
+
+/// Value of a query parameter, or the given header otherwise. Values, which
+/// cannot be parsed, are ignored.
+fn query_or_header<T: FromStr>(request: &Request<'_>, name: &str, header: &str) -> Option<T> {
+    request
+        .query_value::<&str>(name)
+        .and_then(Result::ok)
+        .or_else(|| request.headers().get_one(header))
+        .and_then(|value| value.parse().ok())
+}
+
+/// Telemetry of the requesting device, taken from the query parameters
+/// `battery`, `wake_reason`, `rssi` and `firmware`, or the headers
+/// `X-Inkplate-Battery`, `X-Inkplate-Wake-Reason`, `X-Inkplate-Rssi` and
+/// `X-Inkplate-Firmware`
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Telemetry {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Telemetry, Self::Error> {
+        Outcome::Success(Telemetry {
+            battery_voltage: query_or_header(request, "battery", "X-Inkplate-Battery"),
+            wake_reason: query_or_header(request, "wake_reason", "X-Inkplate-Wake-Reason"),
+            rssi: query_or_header(request, "rssi", "X-Inkplate-Rssi"),
+            firmware_version: query_or_header(request, "firmware", "X-Inkplate-Firmware"),
+        })
     }
-    return Some((ContentType::PNG, processed_image.dithered));
 }
 
-#[rocket::get("/lexica/inkplate")]
-async fn lexica_inkplate(connection: DbConn) -> Option<Vec<u8>> {
-    let lexica = fetch_lexica().unwrap();
+#[derive(Responder)]
+enum InkplateResponse {
+    /// Frame sent to the display together with its entity tag, the
+    /// compression applied to it, the time to sleep and whether the device
+    /// has to draw the battery warning
+    Frame(
+        Vec<u8>,
+        Header<'static>,
+        Header<'static>,
+        Header<'static>,
+        Header<'static>,
+    ),
+    /// The display already shows the current frame
+    #[response(status = 304)]
+    NotModified((), Header<'static>, Header<'static>),
+}
+
+/// Query parameters accepted by all image endpoints
+#[derive(FromForm)]
+struct ImageQuery<'r> {
+    search: Option<String>,
+    display: Option<&'r str>,
+    dithering: Option<&'r str>,
+    palette: Option<&'r str>,
+    metric: Option<&'r str>,
+    serpentine: Option<bool>,
+    auto_levels: Option<bool>,
+    equalize: Option<bool>,
+    gamma: Option<f32>,
+    saturation: Option<f32>,
+    sharpen: Option<f32>,
+}
+
+/// Parameters of a request, which influence the selection and processing of
+/// the image to be shown.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ImageRequest {
+    /// Device the image is shown on, if it is requested by one
+    pub device: Option<String>,
+    /// Image sources selected for the device, instead of the configured ones
+    pub image_source: Option<String>,
+    pub search: Option<String>,
+    pub display: DisplayProfile,
+    pub dithering: DitheringAlgorithm,
+    pub palette: Palette,
+    pub color_metric: ColorMetric,
+    pub serpentine: bool,
+    pub adjustments: Adjustments,
+    pub overlays: Overlays,
+    /// Whether the device has reported a low battery, which is warned about
+    /// by the overlays. The frame is replaced, once this changes.
+    pub battery_low: bool,
+    /// Time zone the time of the last update is shown in
+    pub time_zone: Tz,
+}
+
+impl ImageRequest {
+    /// Build a request from the query parameters of a client. Parameters not
+    /// given are taken from the persisted config.
+    fn from_query(config: &PersistedConfig, query: ImageQuery) -> Result<Self, Status> {
+        Self::with_query(config.default_request(), query)
+    }
+
+    /// Build a request from the query parameters of a device. Parameters not
+    /// given are taken from the settings of the device, or the persisted
+    /// config.
+    fn from_device_query(
+        config: &PersistedConfig,
+        device: &Device,
+        query: ImageQuery,
+    ) -> Result<Self, Status> {
+        Self::with_query(device.default_request(config), query)
+    }
+
+    fn with_query(mut request: ImageRequest, query: ImageQuery) -> Result<Self, Status> {
+        if query.search.is_some() {
+            request.search = query.search;
+        }
+        if let Some(display) = query.display {
+            request.display = display.parse().map_err(bad_request)?;
+            // The configured palette might not be supported by this display
+            if request.display.check_palette(request.palette).is_err() {
+                request.palette = request.display.default_palette();
+            }
+        }
+        if let Some(dithering) = query.dithering {
+            request.dithering = dithering.parse().map_err(bad_request)?;
+        }
+        if let Some(palette) = query.palette {
+            request.palette = palette.parse().map_err(bad_request)?;
+        }
+        request
+            .display
+            .check_palette(request.palette)
+            .map_err(bad_request)?;
+        if let Some(metric) = query.metric {
+            request.color_metric = metric.parse().map_err(bad_request)?;
+        }
+        if let Some(serpentine) = query.serpentine {
+            request.serpentine = serpentine;
+        }
+
+        let adjustments = &mut request.adjustments;
+        adjustments.auto_levels = query.auto_levels.unwrap_or(adjustments.auto_levels);
+        adjustments.equalize = query.equalize.unwrap_or(adjustments.equalize);
+        adjustments.gamma = query.gamma.unwrap_or(adjustments.gamma);
+        adjustments.saturation = query.saturation.unwrap_or(adjustments.saturation);
+        adjustments.sharpen = query.sharpen.unwrap_or(adjustments.sharpen);
+        adjustments.validate().map_err(bad_request)?;
+
+        Ok(request)
+    }
+}
+
+/// An image selected from the candidates of an image source, which has been
+/// processed and is ready to be served.
+pub struct PreparedImage {
+    pub lexica: Vec<LazyLexicaImage>,
+    pub image_index: usize,
+    pub processed_image: ProcessedImage,
+}
+
+impl PreparedImage {
+    /// The candidate, which has actually been selected and processed
+    pub fn shown_image(&self) -> &LazyLexicaImage {
+        &self.lexica[self.image_index]
+    }
+}
+
+pub fn prepare_image(
+    connection: &Connection,
+    image_source: &dyn ImageSource,
+    overlay_font: &OverlayFont,
+    request: &ImageRequest,
+) -> anyhow::Result<PreparedImage> {
+    let lexica = image_source.fetch_candidates(connection, request)?;
+    if lexica.is_empty() {
+        return Err(anyhow!("No candidates from {}", image_source.name()));
+    }
     let mut rng = rand::thread_rng();
     let image_index = rng.gen_range(0..lexica.len());
-    let processed_image = process_lazy_lexica_image(&lexica[image_index]);
+    let processed_image = process_lazy_lexica_image(&lexica[image_index], overlay_font, request)?;
 
-    {
-        let processed_image = processed_image.clone();
-        tokio::spawn(async move {
-            store_image_and_prompt(&connection, &lexica[0]);
-            give_image_to_posterity(&connection, &lexica[0], &processed_image);
-        });
+    Ok(PreparedImage {
+        lexica,
+        image_index,
+        processed_image,
+    })
+}
+
+fn record_shown_image(connection: DbConn, prepared_image: PreparedImage, device: Option<String>) {
+    tokio::spawn(async move {
+        if let Err(error) =
+            give_prepared_image_to_posterity(&connection, &prepared_image, device.as_deref())
+        {
+            println!(
+                "Could not store image {}: {:?}",
+                prepared_image.shown_image().id,
+                error
+            );
+        }
+    });
+}
+
+fn unavailable(error: anyhow::Error) -> Status {
+    println!("Could not provide an image: {:?}", error);
+    Status::ServiceUnavailable
+}
+
+fn internal_error(error: anyhow::Error) -> Status {
+    println!("Internal error: {:?}", error);
+    Status::InternalServerError
+}
+
+fn bad_request(error: anyhow::Error) -> Status {
+    println!("Invalid request: {:?}", error);
+    Status::BadRequest
+}
+
+#[rocket::get("/lexica/png/cropped?<query..>")]
+async fn lexica_png_original(
+    connection: DbConn,
+    image_source: &State<Arc<dyn ImageSource>>,
+    overlay_font: &State<Arc<OverlayFont>>,
+    config: &State<Mutex<PersistedConfig>>,
+    query: ImageQuery<'_>,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let request = ImageRequest::from_query(&config.lock().unwrap(), query)?;
+    let prepared_image = prepare_image(&connection, image_source.as_ref(), overlay_font, &request)
+        .map_err(unavailable)?;
+    let cropped = prepared_image.processed_image.cropped.clone();
+    record_shown_image(connection, prepared_image, None);
+
+    return Ok((ContentType::PNG, cropped));
+}
+
+#[rocket::get("/lexica/png/adjusted?<query..>")]
+async fn lexica_png_adjusted(
+    connection: DbConn,
+    image_source: &State<Arc<dyn ImageSource>>,
+    overlay_font: &State<Arc<OverlayFont>>,
+    config: &State<Mutex<PersistedConfig>>,
+    query: ImageQuery<'_>,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let request = ImageRequest::from_query(&config.lock().unwrap(), query)?;
+    let prepared_image = prepare_image(&connection, image_source.as_ref(), overlay_font, &request)
+        .map_err(unavailable)?;
+    let adjusted = prepared_image.processed_image.adjusted.clone();
+    record_shown_image(connection, prepared_image, None);
+
+    return Ok((ContentType::PNG, adjusted));
+}
+
+#[rocket::get("/lexica/png/dithered?<query..>")]
+async fn lexica_png_dithered(
+    connection: DbConn,
+    image_source: &State<Arc<dyn ImageSource>>,
+    overlay_font: &State<Arc<OverlayFont>>,
+    config: &State<Mutex<PersistedConfig>>,
+    query: ImageQuery<'_>,
+) -> Result<(ContentType, Vec<u8>), Status> {
+    let request = ImageRequest::from_query(&config.lock().unwrap(), query)?;
+    let prepared_image = prepare_image(&connection, image_source.as_ref(), overlay_font, &request)
+        .map_err(unavailable)?;
+    let dithered = prepared_image.processed_image.dithered.clone();
+    record_shown_image(connection, prepared_image, None);
+
+    return Ok((ContentType::PNG, dithered));
+}
+
+#[rocket::get("/lexica/inkplate?<query..>")]
+#[allow(clippy::too_many_arguments)]
+async fn lexica_inkplate(
+    connection: DbConn,
+    app_config: &State<Arc<AppConfig>>,
+    image_source: &State<Arc<dyn ImageSource>>,
+    prefetch_queue: &State<Arc<PrefetchQueue>>,
+    current_frames: &State<CurrentFrames>,
+    overlay_font: &State<Arc<OverlayFont>>,
+    config: &State<Mutex<PersistedConfig>>,
+    device_id: DeviceId<'_>,
+    query: ImageQuery<'_>,
+    compression: Compression,
+    if_none_match: IfNoneMatch<'_>,
+    telemetry: Telemetry,
+) -> Result<InkplateResponse, Status> {
+    let device = register_device(&connection, device_id.0).map_err(unavailable)?;
+    if let Err(error) = record_telemetry(&connection, &device.id, &telemetry) {
+        println!("Could not store telemetry of {}: {:?}", device.id, error);
+    }
+    if let Some(voltage) = telemetry.battery_voltage {
+        if is_battery_low(voltage, app_config.battery_warning_voltage) {
+            println!("Battery of {} is low: {:.2} V", device.id, voltage);
+        }
     }
+    let config = config.lock().unwrap().clone();
+    let mut request = ImageRequest::from_device_query(&config, &device, query)?;
+    request.battery_low = request.overlays.battery_low(
+        telemetry.battery_voltage,
+        app_config.battery_warning_voltage,
+    );
+    // Prefetching is limited to the settings of the device, any parameters
+    // given by the query are prepared on demand
+    prefetch_queue.register(ImageRequest {
+        battery_low: request.battery_low,
+        ..device.default_request(&config)
+    });
+    let schedule = device.config(&config).schedule().map_err(unavailable)?;
+    let now = Utc::now();
+
+    // Frames are kept throughout the night
+    let max_age = if schedule.is_paused(now) {
+        Duration::MAX
+    } else {
+        schedule.update_interval()
+    };
+    let frame = match current_frames.current(&device.id, &request, max_age, now.into()) {
+        Some(frame) => frame,
+        None => {
+            let prepared_image = match prefetch_queue.pop(&request) {
+                Some(prepared_image) => prepared_image,
+                None => prepare_image(&connection, image_source.as_ref(), overlay_font, &request)
+                    .map_err(unavailable)?,
+            };
+            let inkplate = prepared_image.processed_image.inkplate.clone();
+            record_shown_image(connection, prepared_image, Some(device.id.clone()));
+            current_frames.replace(&device.id, request, inkplate, now.into())
+        }
+    };
 
-    return Some(processed_image.inkplate);
+    let sleep_seconds = schedule.sleep_seconds(now, DateTime::from(frame.created_at));
+    let sleep = Header::new(SLEEP_HEADER, sleep_seconds.to_string());
+
+    // Weak, as the frame might be sent compressed or uncompressed
+    let etag = Header::new("ETag", format!("W/\"{}\"", frame.etag));
+    if if_none_match.0.is_some_and(|tags| frame.matches(tags)) {
+        return Ok(InkplateResponse::NotModified((), etag, sleep));
+    }
+
+    // Without a font, the warning is left to the firmware
+    let battery_warning = frame.request.battery_low && overlay_font.0.is_none();
+    let (compression, inkplate) = compression.compress(&frame.inkplate);
+    return Ok(InkplateResponse::Frame(
+        inkplate,
+        etag,
+        Header::new(COMPRESSION_HEADER, compression.name()),
+        sleep,
+        Header::new(BATTERY_WARNING_HEADER, battery_warning.to_string()),
+    ));
 }
 
 #[rocket::get("/config")]
@@ -150,29 +734,197 @@ async fn get_config(config: &State<Mutex<PersistedConfig>>) -> Json<PersistedCon
     return Json(config.lock().unwrap().clone());
 }
 
+#[rocket::put("/config", data = "<new_config>")]
+async fn put_config(
+    config: &State<Mutex<PersistedConfig>>,
+    config_file: &State<ConfigFile>,
+    prefetch_queue: &State<Arc<PrefetchQueue>>,
+    new_config: Json<PersistedConfig>,
+) -> Result<Json<PersistedConfig>, Status> {
+    let new_config = new_config.into_inner();
+    new_config.validate().map_err(bad_request)?;
+    new_config.store(&config_file.0).map_err(|error| {
+        println!("Could not store config: {:?}", error);
+        Status::InternalServerError
+    })?;
+    prefetch_queue.reset(new_config.default_request());
+    *config.lock().unwrap() = new_config.clone();
+
+    return Ok(Json(new_config));
+}
+
+#[rocket::get("/devices")]
+async fn get_devices(connection: DbConn) -> Result<Json<Vec<Device>>, Status> {
+    return Ok(Json(list_devices(&connection).map_err(internal_error)?));
+}
+
+#[rocket::get("/devices/<id>")]
+async fn get_device(connection: DbConn, id: &str) -> Result<Json<Device>, Status> {
+    match load_device(&connection, id).map_err(internal_error)? {
+        Some(device) => return Ok(Json(device)),
+        None => return Err(Status::NotFound),
+    }
+}
+
+#[rocket::put("/devices/<id>", data = "<update>")]
+async fn put_device(
+    connection: DbConn,
+    app_config: &State<Arc<AppConfig>>,
+    config: &State<Mutex<PersistedConfig>>,
+    prefetch_queue: &State<Arc<PrefetchQueue>>,
+    id: &str,
+    update: Json<DeviceUpdate>,
+) -> Result<Json<Device>, Status> {
+    let update = update.into_inner();
+    let config = config.lock().unwrap().clone();
+    update
+        .settings
+        .apply(&config)
+        .validate()
+        .map_err(bad_request)?;
+    if let Some(image_source) = &update.settings.image_source {
+        image_source_from_names(image_source, app_config).map_err(bad_request)?;
+    }
+
+    if let Some(previous) = load_device(&connection, id).map_err(internal_error)? {
+        unregister_device(prefetch_queue, &previous, &config);
+    }
+    let device = update_device(&connection, id, &update).map_err(internal_error)?;
+    prefetch_queue.register(device.default_request(&config));
+
+    return Ok(Json(device));
+}
+
+/// Stop prefetching for the device, whether its battery has been low or not
+fn unregister_device(prefetch_queue: &PrefetchQueue, device: &Device, config: &PersistedConfig) {
+    let request = device.default_request(config);
+    for battery_low in [false, true] {
+        prefetch_queue.unregister(&ImageRequest {
+            battery_low,
+            ..request.clone()
+        });
+    }
+}
+
+#[rocket::delete("/devices/<id>")]
+async fn delete_device(
+    connection: DbConn,
+    config: &State<Mutex<PersistedConfig>>,
+    prefetch_queue: &State<Arc<PrefetchQueue>>,
+    id: &str,
+) -> Result<Status, Status> {
+    let device = match load_device(&connection, id).map_err(internal_error)? {
+        Some(device) => device,
+        None => return Err(Status::NotFound),
+    };
+    remove_device(&connection, id).map_err(internal_error)?;
+    unregister_device(prefetch_queue, &device, &config.lock().unwrap());
+
+    return Ok(Status::NoContent);
+}
+
+#[rocket::get("/devices/<id>/history?<limit>")]
+async fn get_device_history(
+    connection: DbConn,
+    id: &str,
+    limit: Option<usize>,
+) -> Result<Json<Vec<ShownImage>>, Status> {
+    let history = device_history(&connection, id, limit.unwrap_or(DEVICE_HISTORY_LIMIT))
+        .map_err(internal_error)?;
+    return Ok(Json(history));
+}
+
+#[rocket::get("/devices/<id>/telemetry?<since>&<limit>")]
+async fn get_device_telemetry(
+    connection: DbConn,
+    id: &str,
+    since: Option<u64>,
+    limit: Option<usize>,
+) -> Result<Json<Vec<TelemetryReport>>, Status> {
+    let telemetry = telemetry_history(
+        &connection,
+        id,
+        since.unwrap_or(0),
+        limit.unwrap_or(TELEMETRY_LIMIT),
+    )
+    .map_err(internal_error)?;
+    return Ok(Json(telemetry));
+}
+
+#[rocket::get("/devices/<id>/battery")]
+async fn get_device_battery(
+    connection: DbConn,
+    app_config: &State<Arc<AppConfig>>,
+    id: &str,
+) -> Result<Json<BatteryStatus>, Status> {
+    match battery_status(&connection, id, app_config.battery_warning_voltage)
+        .map_err(internal_error)?
+    {
+        Some(status) => return Ok(Json(status)),
+        None => return Err(Status::NotFound),
+    }
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     flexi_logger::Logger::try_with_str("info, oxipng=error")?.start()?;
 
     let figment = Figment::from(Env::prefixed("LEXICA_INKPLATE_"));
-    let config: AppConfig = figment.extract()?;
-    let db_file = format!("{}/posterity.sqlite", config.storage_path);
-    let persistent_config = Mutex::new(PersistedConfig {
-        update_at_night: false,
-        update_interval: 15,
+    let config: Arc<AppConfig> = Arc::new(figment.extract()?);
+    let db_file = config.db_file();
+    let image_source: Arc<dyn ImageSource> =
+        Arc::new(SelectableImageSource::new(Arc::clone(&config))?);
+    println!("Using image source: {}", config.image_source);
+
+    let config_file = Path::new(&config.storage_path).join("config.json");
+    let persisted_config = PersistedConfig::load(&config_file)?;
+    persisted_config.validate()?;
+
+    let prefetch_queue = Arc::new(PrefetchQueue::new(
+        config.prefetch_count,
+        persisted_config.default_request(),
+    ));
+    let overlay_font = Arc::new(match &config.overlay_font {
+        Some(path) => OverlayFont(Some(image_data::font_from_file(path)?)),
+        None => {
+            println!("No overlay font configured, overlays are not shown");
+            OverlayFont(None)
+        }
     });
 
+    spawn_prefetch_worker(
+        Arc::clone(&prefetch_queue),
+        Arc::clone(&image_source),
+        Arc::clone(&overlay_font),
+        &db_file,
+    )?;
+    let persistent_config = Mutex::new(persisted_config);
+
     let _rocket = rocket::build()
         .manage(config)
         .manage(DbFile(db_file))
+        .manage(image_source)
+        .manage(prefetch_queue)
+        .manage(CurrentFrames::default())
+        .manage(overlay_font)
         .manage(persistent_config)
+        .manage(ConfigFile(config_file))
         .mount(
             "/",
             rocket::routes![
                 lexica_png_original,
+                lexica_png_adjusted,
                 lexica_png_dithered,
                 lexica_inkplate,
                 get_config,
+                put_config,
+                get_devices,
+                get_device,
+                put_device,
+                delete_device,
+                get_device_history,
+                get_device_telemetry,
+                get_device_battery,
             ],
         )
         .launch()
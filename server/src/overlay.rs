@@ -0,0 +1,132 @@
+use std::ops::RangeInclusive;
+
+use anyhow::anyhow;
+use chrono::DateTime;
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+
+use crate::telemetry::is_battery_low;
+
+/// Accepted heights of the text in pixels
+const FONT_SIZES: RangeInclusive<u32> = 8..=128;
+
+/// Status texts composited onto the frame of a device, before it is dithered
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Overlays {
+    /// Warn about a low battery, as reported by the telemetry of the device
+    pub battery_warning: bool,
+    /// Show the time the frame has been created at
+    pub last_update: bool,
+    /// Text shown on every frame
+    pub caption: Option<String>,
+    /// Height of the text in pixels
+    pub font_size: u32,
+}
+
+impl Default for Overlays {
+    fn default() -> Self {
+        Self {
+            battery_warning: true,
+            last_update: false,
+            caption: None,
+            font_size: 24,
+        }
+    }
+}
+
+impl Overlays {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !FONT_SIZES.contains(&self.font_size) {
+            return Err(anyhow!(
+                "Font size must be within {:?}: {}",
+                FONT_SIZES,
+                self.font_size
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the battery voltage reported by the device is to be warned
+    /// about
+    pub fn battery_low(&self, battery_voltage: Option<f64>, warning_voltage: f64) -> bool {
+        self.battery_warning
+            && battery_voltage.is_some_and(|voltage| is_battery_low(voltage, warning_voltage))
+    }
+
+    /// Lines of text to be drawn onto the frame, from top to bottom
+    pub fn lines(&self, battery_low: bool, updated_at: DateTime<Tz>) -> Vec<String> {
+        let mut lines = Vec::new();
+        if let Some(caption) = &self.caption {
+            lines.extend(caption.lines().map(str::to_string));
+        }
+        if self.last_update {
+            lines.push(format!("Updated {}", updated_at.format("%Y-%m-%d %H:%M")));
+        }
+        if battery_low {
+            lines.push("Battery low!".to_string());
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn updated_at() -> DateTime<Tz> {
+        chrono_tz::Europe::Berlin
+            .with_ymd_and_hms(2023, 1, 15, 8, 30, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn warns_about_low_batteries_only() {
+        let overlays = Overlays::default();
+        assert!(overlays.battery_low(Some(3.52), 3.6));
+        assert!(!overlays.battery_low(Some(3.9), 3.6));
+        assert!(!overlays.battery_low(None, 3.6));
+        // Reported without a battery
+        assert!(!overlays.battery_low(Some(0.0), 3.6));
+        assert_eq!(overlays.lines(true, updated_at()), vec!["Battery low!"]);
+        assert!(overlays.lines(false, updated_at()).is_empty());
+
+        let overlays = Overlays {
+            battery_warning: false,
+            ..Overlays::default()
+        };
+        assert!(!overlays.battery_low(Some(3.52), 3.6));
+    }
+
+    #[test]
+    fn rejects_font_sizes_out_of_range() {
+        assert!(Overlays::default().validate().is_ok());
+        for font_size in [0, 7, 129, u32::MAX] {
+            let overlays = Overlays {
+                font_size,
+                ..Overlays::default()
+            };
+            assert!(overlays.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn orders_caption_update_and_warning() {
+        let overlays = Overlays {
+            last_update: true,
+            caption: Some("Living room\nHello".to_string()),
+            ..Overlays::default()
+        };
+        assert_eq!(
+            overlays.lines(true, updated_at()),
+            vec![
+                "Living room",
+                "Hello",
+                "Updated 2023-01-15 08:30",
+                "Battery low!"
+            ]
+        );
+    }
+}
@@ -1,13 +1,18 @@
 use std::time::SystemTime;
 
-use rusqlite::{params, Connection};
+use anyhow::anyhow;
+use rusqlite::{params, Connection, OptionalExtension, Params};
 use rusqlite_migration::{Migrations, M};
+use serde_json::Value;
 
+use crate::directory;
+use crate::image_source::ImageSource;
 use crate::lexica::LazyLexicaImage;
-use crate::{image_data, DbConn, ProcessedImage};
+use crate::{image_data, ImageRequest, PreparedImage, ProcessedImage};
 
-pub fn create_posterity_db(connection: &mut Connection) {
-    let migrations = Migrations::new(vec![
+/// Migrations are identified by their position, new ones must be appended
+fn migrations() -> Vec<M<'static>> {
+    vec![
         M::up(
             "CREATE TABLE IF NOT EXISTS lexica_image (
         id TEXT PRIMARY KEY,
@@ -38,85 +43,545 @@ pub fn create_posterity_db(connection: &mut Connection) {
         M::up("CREATE INDEX IF NOT EXISTS idx_posterity_lexixa_image ON posterity(lexica_image)"),
         M::up("ALTER TABLE lexica_image ADD image_type TEXT NOT NULL DEFAULT \"png\""),
         M::up("ALTER TABLE posterity ADD image_type TEXT NOT NULL DEFAULT \"png\""),
-    ]);
+        // Image data and image type used to be stored swapped
+        M::up(
+            "UPDATE lexica_image SET image = image_type, image_type = 'jxl'
+        WHERE typeof(image) = 'text' AND image = 'jxl'",
+        ),
+        M::up("ALTER TABLE lexica_image ADD search TEXT NOT NULL DEFAULT \"\""),
+        M::up(
+            "CREATE TABLE IF NOT EXISTS rejected_image (
+        id TEXT PRIMARY KEY,
+        reason TEXT NOT NULL,
+        rejected_at INTEGER
+    )",
+        ),
+        // Used to be listed before `rejected_image`, databases migrated at
+        // that time already contain both tables
+        M::up(
+            "CREATE TABLE IF NOT EXISTS lexica_cursor (
+        search TEXT PRIMARY KEY,
+        cursor INTEGER NOT NULL,
+        page INTEGER NOT NULL,
+        updated_at INTEGER
+    )",
+        ),
+        M::up(
+            "CREATE TABLE IF NOT EXISTS device (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL DEFAULT \"\",
+        settings TEXT NOT NULL DEFAULT \"{}\",
+        current_image TEXT,
+        registered_at INTEGER,
+        last_seen_at INTEGER
+    )",
+        ),
+        // Images shown before devices have been told apart have no device
+        M::up("ALTER TABLE posterity ADD device TEXT"),
+        M::up("CREATE INDEX IF NOT EXISTS idx_posterity_device ON posterity(device)"),
+        M::up(
+            "CREATE TABLE IF NOT EXISTS telemetry (
+        id INTEGER PRIMARY KEY,
+        device TEXT NOT NULL,
+        battery_voltage REAL,
+        wake_reason TEXT,
+        rssi INTEGER,
+        firmware_version TEXT,
+        reported_at INTEGER NOT NULL
+    )",
+        ),
+        M::up("CREATE INDEX IF NOT EXISTS idx_telemetry_device ON telemetry(device, reported_at)"),
+    ]
+}
 
-    migrations.to_latest(connection).unwrap();
+pub fn create_posterity_db(connection: &mut Connection) {
+    Migrations::new(migrations()).to_latest(connection).unwrap();
 }
 
-pub fn store_image_and_prompt(connection: &DbConn, lexica_image: &LazyLexicaImage) {
+/// Store the given image and its prompt. The search the image has been found
+/// with is stored as well, to allow images set aside for later to be used for
+/// the same search only.
+pub fn store_image_and_prompt(
+    connection: &Connection,
+    lexica_image: &LazyLexicaImage,
+    search: &str,
+) -> anyhow::Result<()> {
     let image_id = &lexica_image.id;
-    let prompt_id = lexica_image.prompt["id"].as_str().unwrap();
+    let already_stored = connection
+        .query_row(
+            "SELECT 1 FROM lexica_image WHERE id = ?1",
+            params![image_id],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some();
+    if already_stored {
+        return Ok(());
+    }
+
+    let prompt_id = lexica_image.prompt["id"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Prompt of image {} has no id", image_id))?;
+    let prompt = lexica_image.prompt["prompt"]
+        .as_str()
+        .ok_or_else(|| anyhow!("Prompt {} has no text", prompt_id))?;
     let now = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs();
 
-    connection
-        .execute(
-            "
+    connection.execute(
+        "
             INSERT OR IGNORE INTO lexica_prompt
                 (id, prompt, raw_document, stored_at)
             VALUES
                 (?1, ?2, ?3, ?4)
             ",
-            params![
-                prompt_id,
-                &lexica_image.prompt["prompt"].as_str().unwrap(),
-                serde_json::to_string(&lexica_image.prompt).unwrap(),
-                now
-            ],
-        )
-        .unwrap();
+        params![
+            prompt_id,
+            prompt,
+            serde_json::to_string(&lexica_image.prompt)?,
+            now
+        ],
+    )?;
 
-    let image_data_jpegxl = image_data::jpegxl(&lexica_image.image().unwrap());
+    let image = lexica_image.image()?;
+    let image_data_jpegxl = image_data::jpegxl(&image)?;
 
-    connection
-        .execute(
-            "
+    connection.execute(
+        "
             INSERT OR IGNORE INTO lexica_image
-                (id, prompt, url, raw_document, image, image_type, stored_at)
+                (id, prompt, url, raw_document, image, image_type, search, stored_at)
             VALUES
-                (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
             ",
-            params![
-                image_id,
-                prompt_id,
-                lexica_image.url,
-                serde_json::to_string(&lexica_image.metadata).unwrap(),
-                "jxl",
-                image_data_jpegxl,
-                now
-            ],
-        )
-        .unwrap();
+        params![
+            image_id,
+            prompt_id,
+            lexica_image.url,
+            serde_json::to_string(&lexica_image.metadata).unwrap(),
+            image_data_jpegxl,
+            "jxl",
+            search,
+            now
+        ],
+    )?;
+
+    Ok(())
 }
 
 pub fn give_image_to_posterity(
-    connection: &DbConn,
+    connection: &Connection,
     lexica_image: &LazyLexicaImage,
     processed_image: &ProcessedImage,
-) {
+    device: Option<&str>,
+) -> anyhow::Result<()> {
     let image_id = &lexica_image.id;
     let now = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs();
 
-    connection
-        .execute(
+    connection.execute(
+        "
+        INSERT INTO posterity
+            (lexica_image, cropped_image, dithered_image, image_type, device, shown_at)
+        VALUES
+            (?1, ?2, ?3, ?4, ?5, ?6)
+        ",
+        params![
+            image_id,
+            image_data::jpegxl_from_data(&processed_image.cropped)?,
+            image_data::jpegxl_from_data(&processed_image.dithered)?,
+            "jxl",
+            device,
+            now
+        ],
+    )?;
+    Ok(())
+}
+
+/// Store the image, which has been shown on the given device, as well as its
+/// processed versions
+pub fn give_prepared_image_to_posterity(
+    connection: &Connection,
+    prepared_image: &PreparedImage,
+    device: Option<&str>,
+) -> anyhow::Result<()> {
+    let shown_image = prepared_image.shown_image();
+    // Private photos must not be served by the lexica posterity fallback
+    if !directory::is_directory_image(&shown_image.id) {
+        store_image_and_prompt(connection, shown_image, "")?;
+    }
+    give_image_to_posterity(
+        connection,
+        shown_image,
+        &prepared_image.processed_image,
+        device,
+    )?;
+    if let Some(device) = device {
+        connection.execute(
+            "UPDATE device SET current_image = ?1 WHERE id = ?2",
+            params![shown_image.id, device],
+        )?;
+    }
+    Ok(())
+}
+
+/// Number of archived images offered as candidates by the posterity source
+const POSTERITY_CANDIDATES: usize = 10;
+
+/// Decode an image blob stored in the database according to its image type
+fn decode_stored_image(image: &[u8], image_type: &str) -> anyhow::Result<image::DynamicImage> {
+    match image_type {
+        "jxl" => image_data::image_from_jpegxl(image),
+        _ => Ok(image::load_from_memory(image)?),
+    }
+}
+
+/// Load stored images using the given query, which needs to select the image
+/// id, url, raw document, image, image type and the raw prompt document.
+fn stored_images<P: Params>(
+    connection: &Connection,
+    query: &str,
+    params: P,
+) -> anyhow::Result<Vec<LazyLexicaImage>> {
+    let mut statement = connection.prepare(query)?;
+    let rows = statement.query_map(params, |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, Vec<u8>>(3)?,
+            row.get::<_, String>(4)?,
+            row.get::<_, String>(5)?,
+        ))
+    })?;
+
+    let mut images = Vec::new();
+    for row in rows {
+        let (id, url, raw_image_document, image, image_type, raw_prompt_document) = row?;
+        let metadata: Value = serde_json::from_str(&raw_image_document)?;
+        let prompt: Value = serde_json::from_str(&raw_prompt_document)?;
+        images.push(LazyLexicaImage::with_loader(
+            id,
+            url,
+            prompt,
+            metadata,
+            Box::new(move || decode_stored_image(&image, &image_type)),
+        ));
+    }
+
+    Ok(images)
+}
+
+/// Cursor and page number of the next lexica page to fetch for a search
+pub fn load_lexica_cursor(connection: &Connection, search: &str) -> anyhow::Result<(u64, u32)> {
+    let cursor = connection
+        .query_row(
+            "SELECT cursor, page FROM lexica_cursor WHERE search = ?1",
+            [search],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+
+    Ok(cursor.unwrap_or((0, 0)))
+}
+
+pub fn store_lexica_cursor(
+    connection: &Connection,
+    search: &str,
+    cursor: u64,
+    page: u32,
+) -> anyhow::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    connection.execute(
+        "
+        INSERT OR REPLACE INTO lexica_cursor
+            (search, cursor, page, updated_at)
+        VALUES
+            (?1, ?2, ?3, ?4)
+        ",
+        params![search, cursor, page, now],
+    )?;
+    Ok(())
+}
+
+/// Number of stored images for the given search, which have never been shown
+pub fn count_backlog_images(connection: &Connection, search: &str) -> anyhow::Result<usize> {
+    Ok(connection.query_row(
+        "
+        SELECT COUNT(l.id)
+        FROM lexica_image AS l
+        WHERE l.search = ?1
+            AND NOT EXISTS (SELECT p.id FROM posterity p WHERE p.lexica_image = l.id)
+            AND NOT EXISTS (SELECT r.id FROM rejected_image r WHERE r.id = l.id)
+        ",
+        [search],
+        |row| row.get(0),
+    )?)
+}
+
+/// Random selection of stored images for the given search, which have never
+/// been shown
+pub fn backlog_images(
+    connection: &Connection,
+    search: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<LazyLexicaImage>> {
+    stored_images(
+        connection,
+        "
+        SELECT i.id, i.url, i.raw_document, i.image, i.image_type, p.raw_document
+        FROM lexica_image i
+        JOIN lexica_prompt p ON p.id = i.prompt
+        WHERE i.image_type IN ('jxl', 'png')
+            AND i.search = ?1
+            AND NOT EXISTS (SELECT s.id FROM posterity s WHERE s.lexica_image = i.id)
+            AND NOT EXISTS (SELECT r.id FROM rejected_image r WHERE r.id = i.id)
+        ORDER BY RANDOM()
+        LIMIT ?2
+        ",
+        params![search, limit],
+    )
+}
+
+/// Previously fetched images stored in the `lexica_image` table.
+///
+/// This allows the frame to continue showing changing images, while
+/// lexica.art is unreachable. Images, which have not been shown on the
+/// requesting device for the longest time, are preferred. Images currently
+/// shown on other devices are skipped.
+pub struct PosterityImageSource;
+
+impl ImageSource for PosterityImageSource {
+    fn name(&self) -> &'static str {
+        "posterity"
+    }
+
+    fn fetch_candidates(
+        &self,
+        connection: &Connection,
+        request: &ImageRequest,
+    ) -> anyhow::Result<Vec<LazyLexicaImage>> {
+        // Without a device the history of all devices is considered
+        stored_images(
+            connection,
             "
-            INSERT INTO posterity
-                (lexica_image, cropped_image, dithered_image, image_type, shown_at)
-            VALUES
-                (?1, ?2, ?3, ?4, ?5)
+            SELECT i.id, i.url, i.raw_document, i.image, i.image_type, p.raw_document
+            FROM lexica_image i
+            JOIN lexica_prompt p ON p.id = i.prompt
+            WHERE i.image_type IN ('jxl', 'png')
+                AND NOT EXISTS (SELECT r.id FROM rejected_image r WHERE r.id = i.id)
+                AND NOT EXISTS (
+                    SELECT d.id FROM device d WHERE d.current_image = i.id AND d.id IS NOT ?2
+                )
+            ORDER BY
+                IFNULL((
+                    SELECT MAX(s.shown_at) FROM posterity s
+                    WHERE s.lexica_image = i.id AND (?2 IS NULL OR s.device = ?2)
+                ), 0),
+                RANDOM()
+            LIMIT ?1
             ",
-            params![
-                image_id,
-                image_data::jpegxl_from_data(&processed_image.cropped),
-                image_data::jpegxl_from_data(&processed_image.dithered),
-                "jxl",
-                now
-            ],
+            params![POSTERITY_CANDIDATES, request.device],
         )
-        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::GenericImage;
+    use serde_json::json;
+
+    use super::*;
+
+    fn lazy_test_image(id: &str) -> LazyLexicaImage {
+        LazyLexicaImage::with_loader(
+            id.to_string(),
+            format!("https://example.com/{}", id),
+            json!({ "id": format!("prompt-{}", id), "prompt": "A test prompt" }),
+            json!({ "id": id }),
+            Box::new(|| Ok(image::DynamicImage::ImageRgb8(image::RgbImage::new(4, 4)))),
+        )
+    }
+
+    #[test]
+    fn gives_shown_image_to_posterity() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        create_posterity_db(&mut connection);
+
+        let test_image =
+            image_data::png(&image::DynamicImage::ImageRgb8(image::RgbImage::new(4, 4)));
+        let prepared_image = PreparedImage {
+            lexica: vec![
+                lazy_test_image("first"),
+                lazy_test_image("second"),
+                lazy_test_image("third"),
+            ],
+            image_index: 1,
+            processed_image: ProcessedImage {
+                cropped: test_image.clone(),
+                adjusted: test_image.clone(),
+                dithered: test_image.clone(),
+                rotated: test_image,
+                inkplate: vec![],
+            },
+        };
+
+        give_prepared_image_to_posterity(&connection, &prepared_image, None).unwrap();
+
+        let shown: Vec<String> = connection
+            .prepare("SELECT lexica_image FROM posterity")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(shown, vec!["second".to_string()]);
+
+        let stored: Vec<String> = connection
+            .prepare("SELECT id FROM lexica_image")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(stored, vec!["second".to_string()]);
+    }
+
+    #[test]
+    fn keeps_directory_images_out_of_lexica_images() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        create_posterity_db(&mut connection);
+
+        let test_image =
+            image_data::png(&image::DynamicImage::ImageRgb8(image::RgbImage::new(4, 4)));
+        let prepared_image = PreparedImage {
+            lexica: vec![lazy_test_image("directory:holiday/beach.jpg")],
+            image_index: 0,
+            processed_image: ProcessedImage {
+                cropped: test_image.clone(),
+                adjusted: test_image.clone(),
+                dithered: test_image.clone(),
+                rotated: test_image,
+                inkplate: vec![],
+            },
+        };
+
+        give_prepared_image_to_posterity(&connection, &prepared_image, None).unwrap();
+
+        let shown: i64 = connection
+            .query_row("SELECT COUNT(*) FROM posterity", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(shown, 1);
+        let stored: i64 = connection
+            .query_row("SELECT COUNT(*) FROM lexica_image", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(stored, 0);
+    }
+
+    #[test]
+    fn does_not_reload_stored_images() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        create_posterity_db(&mut connection);
+        store_image_and_prompt(&connection, &lazy_test_image("stored"), "").unwrap();
+
+        let unloadable = LazyLexicaImage::with_loader(
+            "stored".to_string(),
+            "https://example.com/stored".to_string(),
+            json!({ "id": "prompt-stored", "prompt": "A test prompt" }),
+            json!({ "id": "stored" }),
+            Box::new(|| Err(anyhow!("Stored images must not be loaded again"))),
+        );
+        store_image_and_prompt(&connection, &unloadable, "").unwrap();
+    }
+
+    #[test]
+    fn rejects_prompts_without_id() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        create_posterity_db(&mut connection);
+
+        let lexica_image = LazyLexicaImage::with_loader(
+            "no-prompt".to_string(),
+            "https://example.com/no-prompt".to_string(),
+            json!({ "prompt": "A test prompt" }),
+            json!({ "id": "no-prompt" }),
+            Box::new(|| Ok(image::DynamicImage::ImageRgb8(image::RgbImage::new(4, 4)))),
+        );
+        assert!(store_image_and_prompt(&connection, &lexica_image, "").is_err());
+    }
+
+    #[test]
+    fn stores_image_data_with_its_type() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        create_posterity_db(&mut connection);
+
+        store_image_and_prompt(&connection, &lazy_test_image("stored"), "").unwrap();
+
+        let (image, image_type): (Vec<u8>, String) = connection
+            .query_row(
+                "SELECT image, image_type FROM lexica_image WHERE id = 'stored'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(image_type, "jxl");
+        assert_eq!(
+            decode_stored_image(&image, &image_type)
+                .unwrap()
+                .dimensions(),
+            (4, 4)
+        );
+    }
+
+    #[test]
+    fn repairs_swapped_image_data() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        // Schema before the swapped columns have been repaired
+        Migrations::new(migrations().into_iter().take(6).collect())
+            .to_latest(&mut connection)
+            .unwrap();
+        connection
+            .execute(
+                "
+                INSERT INTO lexica_image
+                    (id, prompt, url, raw_document, image, image_type, stored_at)
+                VALUES
+                    ('swapped', 'prompt', 'url', '{}', 'jxl', ?1, 0)
+                ",
+                [vec![0xff_u8, 0x0a, 0x42]],
+            )
+            .unwrap();
+
+        create_posterity_db(&mut connection);
+
+        let (image, image_type): (Vec<u8>, String) = connection
+            .query_row(
+                "SELECT image, image_type FROM lexica_image WHERE id = 'swapped'",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(image, vec![0xff, 0x0a, 0x42]);
+        assert_eq!(image_type, "jxl");
+    }
+
+    #[test]
+    fn creates_lexica_cursor_after_rejected_images() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        // Schema before lexica cursors have been added
+        Migrations::new(migrations().into_iter().take(9).collect())
+            .to_latest(&mut connection)
+            .unwrap();
+
+        create_posterity_db(&mut connection);
+
+        store_lexica_cursor(&connection, "search", 42, 1).unwrap();
+        assert_eq!(load_lexica_cursor(&connection, "search").unwrap(), (42, 1));
+    }
 }
@@ -0,0 +1,281 @@
+use std::collections::{HashMap, VecDeque};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rusqlite::Connection;
+
+use crate::image_source::ImageSource;
+use crate::posterity::create_posterity_db;
+use crate::{prepare_image, ImageRequest, OverlayFont, PreparedImage};
+
+/// Time to wait before retrying a request, after an image could not be
+/// prepared for it
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Number of distinct requests prefetched for at most. The least recently
+/// used one is dropped, once another one is registered.
+const MAX_PREFETCHED_REQUESTS: usize = 16;
+
+/// Images prefetched for a single request
+struct Prefetched {
+    images: VecDeque<PreparedImage>,
+    last_used: Instant,
+    /// Set after a failure, to give other requests a chance in the meantime
+    retry_at: Option<Instant>,
+}
+
+/// Queues of fully processed images, which are ready to be served instantly.
+///
+/// A separate queue is kept for each registered image request, like the
+/// default request of the config or of a device. They are filled by a
+/// background worker, which prepares a new image whenever one is taken from
+/// one of the queues. Other requests are not prefetched for, neither are
+/// requests showing the time of the last update, as it is drawn onto the image
+/// while it is prepared.
+pub struct PrefetchQueue {
+    size: usize,
+    requests: Mutex<HashMap<ImageRequest, Prefetched>>,
+    consumed: Condvar,
+}
+
+impl PrefetchQueue {
+    /// Create a queue, which immediately starts prefetching for the given
+    /// initial request.
+    pub fn new(size: usize, initial_request: ImageRequest) -> Self {
+        let queue = Self {
+            size,
+            requests: Mutex::new(HashMap::new()),
+            consumed: Condvar::new(),
+        };
+        queue.register(initial_request);
+        queue
+    }
+
+    /// Start prefetching for the given request, without taking an image
+    pub fn register(&self, request: ImageRequest) {
+        if request.overlays.last_update {
+            return;
+        }
+        let mut requests = self.requests.lock().unwrap();
+        requests
+            .entry(request.clone())
+            .or_insert_with(|| Prefetched {
+                images: VecDeque::with_capacity(self.size),
+                last_used: Instant::now(),
+                retry_at: None,
+            })
+            .last_used = Instant::now();
+
+        if requests.len() > MAX_PREFETCHED_REQUESTS {
+            let least_recently_used = requests
+                .iter()
+                .filter(|(registered, _)| **registered != request)
+                .min_by_key(|(_, prefetched)| prefetched.last_used)
+                .map(|(registered, _)| registered.clone());
+            if let Some(least_recently_used) = least_recently_used {
+                requests.remove(&least_recently_used);
+            }
+        }
+        self.consumed.notify_one();
+    }
+
+    /// Stop prefetching for the given request and drop its images
+    pub fn unregister(&self, request: &ImageRequest) {
+        self.requests.lock().unwrap().remove(request);
+    }
+
+    /// Stop prefetching for all requests, but the given one
+    pub fn reset(&self, request: ImageRequest) {
+        self.requests
+            .lock()
+            .unwrap()
+            .retain(|registered, _| *registered == request);
+        self.register(request);
+    }
+
+    /// Take a prefetched image for the request, if it has been registered
+    pub fn pop(&self, request: &ImageRequest) -> Option<PreparedImage> {
+        let mut requests = self.requests.lock().unwrap();
+        let prefetched = requests.get_mut(request)?;
+        prefetched.last_used = Instant::now();
+        let image = prefetched.images.pop_front();
+        self.consumed.notify_one();
+        image
+    }
+
+    /// Wait until one of the queues needs another image and return its
+    /// request. Queues with the fewest images are filled first, while failed
+    /// requests are only retried after a delay.
+    fn wait_for_space(&self) -> ImageRequest {
+        let mut requests = self.requests.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let mut next: Option<(&ImageRequest, usize)> = None;
+            let mut retry_at: Option<Instant> = None;
+            for (request, prefetched) in requests.iter() {
+                let count = prefetched.images.len();
+                if count >= self.size {
+                    continue;
+                }
+                match prefetched.retry_at {
+                    Some(at) if at > now => {
+                        retry_at = Some(retry_at.map_or(at, |earliest| earliest.min(at)))
+                    }
+                    _ => {
+                        if next.is_none_or(|(_, fewest)| count < fewest) {
+                            next = Some((request, count));
+                        }
+                    }
+                }
+            }
+
+            if let Some((request, _)) = next {
+                return request.clone();
+            }
+            requests = match retry_at {
+                Some(at) => self.consumed.wait_timeout(requests, at - now).unwrap().0,
+                None => self.consumed.wait(requests).unwrap(),
+            };
+        }
+    }
+
+    /// Add the prepared image, unless the request has been dropped meanwhile
+    fn push(&self, request: &ImageRequest, image: PreparedImage) {
+        if let Some(prefetched) = self.requests.lock().unwrap().get_mut(request) {
+            prefetched.images.push_back(image);
+            prefetched.retry_at = None;
+        }
+    }
+
+    fn failed(&self, request: &ImageRequest) {
+        if let Some(prefetched) = self.requests.lock().unwrap().get_mut(request) {
+            prefetched.retry_at = Some(Instant::now() + RETRY_DELAY);
+        }
+    }
+}
+
+fn prefetch_images(
+    queue: &PrefetchQueue,
+    image_source: &dyn ImageSource,
+    overlay_font: &OverlayFont,
+    connection: &Connection,
+) {
+    loop {
+        let request = queue.wait_for_space();
+
+        // A panic while preparing a single image must not stop prefetching
+        let prepared = panic::catch_unwind(AssertUnwindSafe(|| {
+            prepare_image(connection, image_source, overlay_font, &request)
+        }));
+        match prepared {
+            Ok(Ok(image)) => queue.push(&request, image),
+            Ok(Err(error)) => {
+                println!("Could not prefetch image for {:?}: {:?}", request, error);
+                queue.failed(&request);
+            }
+            Err(_) => {
+                println!("Prefetching image for {:?} panicked", request);
+                queue.failed(&request);
+            }
+        }
+    }
+}
+
+/// Start the background worker filling the given queue. Nothing is started if
+/// the queue has a size of zero.
+pub fn spawn_prefetch_worker(
+    queue: Arc<PrefetchQueue>,
+    image_source: Arc<dyn ImageSource>,
+    overlay_font: Arc<OverlayFont>,
+    db_file: &str,
+) -> anyhow::Result<()> {
+    if queue.size == 0 {
+        return Ok(());
+    }
+
+    let mut connection = Connection::open(db_file)?;
+    create_posterity_db(&mut connection);
+    thread::spawn(move || {
+        prefetch_images(&queue, image_source.as_ref(), &overlay_font, &connection)
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overlay::Overlays;
+    use crate::PersistedConfig;
+
+    fn request(search: &str) -> ImageRequest {
+        ImageRequest {
+            search: Some(search.to_string()),
+            ..PersistedConfig::default().default_request()
+        }
+    }
+
+    fn registered(queue: &PrefetchQueue) -> usize {
+        queue.requests.lock().unwrap().len()
+    }
+
+    #[test]
+    fn prefetches_registered_requests_only() {
+        let queue = PrefetchQueue::new(2, request("initial"));
+        assert!(queue.pop(&request("unknown")).is_none());
+        assert_eq!(registered(&queue), 1);
+        assert_eq!(queue.wait_for_space(), request("initial"));
+
+        queue.reset(request("other"));
+        assert_eq!(registered(&queue), 1);
+        assert_eq!(queue.wait_for_space(), request("other"));
+    }
+
+    #[test]
+    fn drops_least_recently_used_requests() {
+        let queue = PrefetchQueue::new(2, request("initial"));
+        for index in 0..MAX_PREFETCHED_REQUESTS {
+            queue.register(request(&index.to_string()));
+        }
+        assert_eq!(registered(&queue), MAX_PREFETCHED_REQUESTS);
+        assert!(!queue
+            .requests
+            .lock()
+            .unwrap()
+            .contains_key(&request("initial")));
+    }
+
+    #[test]
+    fn retries_failed_requests_later() {
+        let queue = PrefetchQueue::new(2, request("failing"));
+        queue.register(request("working"));
+        queue.failed(&request("failing"));
+        assert_eq!(queue.wait_for_space(), request("working"));
+        queue.failed(&request("working"));
+
+        queue
+            .requests
+            .lock()
+            .unwrap()
+            .get_mut(&request("failing"))
+            .unwrap()
+            .retry_at = Some(Instant::now());
+        assert_eq!(queue.wait_for_space(), request("failing"));
+    }
+
+    #[test]
+    fn skips_requests_showing_last_update() {
+        let queue = PrefetchQueue::new(2, request("initial"));
+        let showing_last_update = ImageRequest {
+            overlays: Overlays {
+                last_update: true,
+                ..Overlays::default()
+            },
+            ..request("initial")
+        };
+        queue.register(showing_last_update.clone());
+        assert_eq!(registered(&queue), 1);
+        assert!(queue.pop(&showing_last_update).is_none());
+    }
+}
@@ -0,0 +1,208 @@
+use std::ops::RangeInclusive;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+/// Shortest time a device is asked to sleep
+const MIN_SLEEP_SECONDS: i64 = 60;
+
+/// Accepted update intervals in minutes, up to a week
+const UPDATE_INTERVAL_MINUTES: RangeInclusive<usize> = 1..=7 * 24 * 60;
+
+/// When devices update their frames. Every `update_interval` minutes, except
+/// within the night window, unless `update_at_night` is set.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    update_at_night: bool,
+    update_interval: Duration,
+    night_start: NaiveTime,
+    night_end: NaiveTime,
+    time_zone: Tz,
+}
+
+fn parse_time(time: &str) -> anyhow::Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|error| anyhow!("Invalid time {}, expected HH:MM: {}", time, error))
+}
+
+impl Schedule {
+    /// Create a schedule from the given interval in minutes, the start and end
+    /// of the night as `HH:MM`, and the name of the time zone they are given
+    /// in, e.g. `Europe/Berlin`.
+    pub fn new(
+        update_at_night: bool,
+        update_interval: usize,
+        night_start: &str,
+        night_end: &str,
+        time_zone: &str,
+    ) -> anyhow::Result<Self> {
+        if !UPDATE_INTERVAL_MINUTES.contains(&update_interval) {
+            return Err(anyhow!(
+                "Update interval must be within {:?} minutes: {}",
+                UPDATE_INTERVAL_MINUTES,
+                update_interval
+            ));
+        }
+        Ok(Self {
+            update_at_night,
+            update_interval: Duration::minutes(update_interval as i64),
+            night_start: parse_time(night_start)?,
+            night_end: parse_time(night_end)?,
+            time_zone: time_zone
+                .parse()
+                .map_err(|error| anyhow!("Invalid time zone {}: {}", time_zone, error))?,
+        })
+    }
+
+    pub fn update_interval(&self) -> std::time::Duration {
+        self.update_interval.to_std().unwrap_or_default()
+    }
+
+    /// Whether the local time lies within the night window, which may span
+    /// midnight. The window is empty, if start and end are the same.
+    fn is_night_at(&self, time: NaiveTime) -> bool {
+        if self.night_start <= self.night_end {
+            self.night_start <= time && time < self.night_end
+        } else {
+            time >= self.night_start || time < self.night_end
+        }
+    }
+
+    /// Whether updates are paused at the given point in time
+    pub fn is_paused(&self, now: DateTime<Utc>) -> bool {
+        !self.update_at_night && self.is_night_at(now.with_timezone(&self.time_zone).time())
+    }
+
+    /// The first end of the night after the given point in time
+    fn next_night_end(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let local_now = now.with_timezone(&self.time_zone).naive_local();
+        let mut night_end = local_now.date().and_time(self.night_end);
+        if night_end <= local_now {
+            night_end += Duration::days(1);
+        }
+        self.to_utc(night_end)
+    }
+
+    fn to_utc(&self, local: NaiveDateTime) -> DateTime<Utc> {
+        match self.time_zone.from_local_datetime(&local).earliest() {
+            Some(time) => time.with_timezone(&Utc),
+            // The local time has been skipped by switching to daylight saving
+            // time. Shifting it by an hour lands after the gap.
+            None => self.to_utc(local + Duration::hours(1)),
+        }
+    }
+
+    /// Seconds a device should sleep, until its frame shown since the last
+    /// update is replaced. Updates, which would fall into the night, are
+    /// postponed to its end.
+    pub fn sleep_seconds(&self, now: DateTime<Utc>, last_update: DateTime<Utc>) -> u64 {
+        let mut wake_up = last_update
+            .checked_add_signed(self.update_interval)
+            .unwrap_or(now);
+        if self.is_paused(wake_up) {
+            wake_up = self.next_night_end(wake_up);
+        } else if self.is_paused(now) {
+            // Overdue updates wait for the end of the current night
+            wake_up = wake_up.max(self.next_night_end(now));
+        }
+        (wake_up - now).num_seconds().max(MIN_SLEEP_SECONDS) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn night_schedule(update_at_night: bool, night_start: &str, night_end: &str) -> Schedule {
+        Schedule::new(update_at_night, 30, night_start, night_end, "Europe/Berlin").unwrap()
+    }
+
+    fn berlin(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        chrono_tz::Europe::Berlin
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn rejects_update_intervals_out_of_range() {
+        for interval in [0, 7 * 24 * 60 + 1, usize::MAX] {
+            assert!(Schedule::new(false, interval, "22:00", "06:00", "UTC").is_err());
+        }
+        assert!(Schedule::new(false, 7 * 24 * 60, "22:00", "06:00", "UTC").is_ok());
+    }
+
+    #[test]
+    fn pauses_during_night_spanning_midnight() {
+        let schedule = night_schedule(false, "22:00", "06:00");
+        assert!(!schedule.is_paused(berlin(2023, 1, 15, 21, 59)));
+        assert!(schedule.is_paused(berlin(2023, 1, 15, 22, 0)));
+        assert!(schedule.is_paused(berlin(2023, 1, 16, 3, 0)));
+        assert!(!schedule.is_paused(berlin(2023, 1, 16, 6, 0)));
+
+        let schedule = night_schedule(false, "01:00", "05:00");
+        assert!(!schedule.is_paused(berlin(2023, 1, 15, 23, 0)));
+        assert!(schedule.is_paused(berlin(2023, 1, 16, 3, 0)));
+    }
+
+    #[test]
+    fn updates_at_night_if_enabled() {
+        let schedule = night_schedule(true, "22:00", "06:00");
+        let now = berlin(2023, 1, 15, 23, 0);
+        assert!(!schedule.is_paused(now));
+        assert_eq!(schedule.sleep_seconds(now, now), 30 * 60);
+    }
+
+    #[test]
+    fn sleeps_until_next_update() {
+        let schedule = night_schedule(false, "22:00", "06:00");
+        let last_update = berlin(2023, 1, 15, 12, 0);
+        let now = berlin(2023, 1, 15, 12, 10);
+        assert_eq!(schedule.sleep_seconds(now, last_update), 20 * 60);
+        // Overdue updates are not requested more often than the minimum
+        let now = berlin(2023, 1, 15, 13, 0);
+        assert_eq!(schedule.sleep_seconds(now, last_update), 60);
+    }
+
+    #[test]
+    fn postpones_updates_to_end_of_night() {
+        let schedule = night_schedule(false, "22:00", "06:00");
+        let last_update = berlin(2023, 1, 15, 21, 45);
+        let end_of_night = berlin(2023, 1, 16, 6, 0);
+        assert_eq!(
+            schedule.sleep_seconds(last_update, last_update),
+            (end_of_night - last_update).num_seconds() as u64
+        );
+        let now = berlin(2023, 1, 16, 2, 0);
+        assert_eq!(schedule.sleep_seconds(now, last_update), 4 * 60 * 60);
+    }
+
+    #[test]
+    fn postpones_updates_to_end_of_later_night() {
+        let schedule = Schedule::new(false, 36 * 60, "22:00", "06:00", "Europe/Berlin").unwrap();
+        // Monday noon, the update is due on Wednesday at midnight
+        let last_update = berlin(2023, 1, 16, 12, 0);
+        let now = berlin(2023, 1, 16, 12, 10);
+        assert_eq!(
+            schedule.sleep_seconds(now, last_update),
+            (berlin(2023, 1, 18, 6, 0) - now).num_seconds() as u64
+        );
+
+        // Due after the current night has ended
+        let last_update = berlin(2023, 1, 16, 22, 30);
+        let now = berlin(2023, 1, 16, 23, 0);
+        assert_eq!(
+            schedule.sleep_seconds(now, last_update),
+            (berlin(2023, 1, 18, 10, 30) - now).num_seconds() as u64
+        );
+    }
+
+    #[test]
+    fn ends_night_after_skipped_local_time() {
+        // Clocks in Berlin skip from 02:00 to 03:00 on the 26th of March 2023
+        let schedule = night_schedule(false, "01:00", "02:30");
+        let now = berlin(2023, 3, 26, 1, 30);
+        assert_eq!(schedule.next_night_end(now), berlin(2023, 3, 26, 3, 30));
+    }
+}
@@ -0,0 +1,250 @@
+use std::time::SystemTime;
+
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+/// Voltage rise between two reports, which is taken as the battery having
+/// been charged in between
+const CHARGE_DETECTION_VOLTAGE: f64 = 0.1;
+/// Shortest time span of reports a discharge rate is estimated from
+const MIN_DISCHARGE_SPAN_SECONDS: u64 = 60 * 60;
+/// Reports taken into account when estimating the discharge rate
+const DISCHARGE_REPORTS: usize = 500;
+
+/// Telemetry sent by a device along with its request for a frame
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Telemetry {
+    /// Battery voltage in volts
+    pub battery_voltage: Option<f64>,
+    /// Why the device woke up, e.g. `timer`
+    pub wake_reason: Option<String>,
+    /// Signal strength of the WiFi connection in dBm
+    pub rssi: Option<i32>,
+    pub firmware_version: Option<String>,
+}
+
+impl Telemetry {
+    pub fn is_empty(&self) -> bool {
+        *self == Telemetry::default()
+    }
+}
+
+/// Telemetry stored for a device
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TelemetryReport {
+    pub battery_voltage: Option<f64>,
+    pub wake_reason: Option<String>,
+    pub rssi: Option<i32>,
+    pub firmware_version: Option<String>,
+    pub reported_at: u64,
+}
+
+/// Latest battery voltage of a device, and when it is expected to drop below
+/// the warning voltage
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BatteryStatus {
+    pub voltage: f64,
+    pub reported_at: u64,
+    pub warning_voltage: f64,
+    /// Whether the voltage is below the warning voltage
+    pub low: bool,
+    /// Volts lost per day since the battery has last been charged
+    pub discharge_per_day: Option<f64>,
+    /// Estimated time the warning voltage is reached
+    pub warning_at: Option<u64>,
+}
+
+/// Whether the battery voltage is below the warning voltage. Devices powered
+/// by USB without a battery report 0 V, which is not taken as low.
+pub fn is_battery_low(voltage: f64, warning_voltage: f64) -> bool {
+    voltage > 0.0 && voltage < warning_voltage
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Store the telemetry of the device, unless it has not sent any
+pub fn record_telemetry(
+    connection: &Connection,
+    device: &str,
+    telemetry: &Telemetry,
+) -> anyhow::Result<()> {
+    if telemetry.is_empty() {
+        return Ok(());
+    }
+
+    connection.execute(
+        "
+        INSERT INTO telemetry
+            (device, battery_voltage, wake_reason, rssi, firmware_version, reported_at)
+        VALUES
+            (?1, ?2, ?3, ?4, ?5, ?6)
+        ",
+        params![
+            device,
+            telemetry.battery_voltage,
+            telemetry.wake_reason,
+            telemetry.rssi,
+            telemetry.firmware_version,
+            now()
+        ],
+    )?;
+    Ok(())
+}
+
+/// Telemetry reported by the device since the given point in time, most
+/// recent first
+pub fn telemetry_history(
+    connection: &Connection,
+    device: &str,
+    since: u64,
+    limit: usize,
+) -> anyhow::Result<Vec<TelemetryReport>> {
+    let mut statement = connection.prepare(
+        "
+        SELECT battery_voltage, wake_reason, rssi, firmware_version, reported_at
+        FROM telemetry
+        WHERE device = ?1 AND reported_at >= ?2
+        ORDER BY reported_at DESC, id DESC
+        LIMIT ?3
+        ",
+    )?;
+    let history = statement
+        .query_map(params![device, since, limit], |row| {
+            Ok(TelemetryReport {
+                battery_voltage: row.get(0)?,
+                wake_reason: row.get(1)?,
+                rssi: row.get(2)?,
+                firmware_version: row.get(3)?,
+                reported_at: row.get(4)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(history)
+}
+
+/// Battery status of the device, if it has reported its voltage
+pub fn battery_status(
+    connection: &Connection,
+    device: &str,
+    warning_voltage: f64,
+) -> anyhow::Result<Option<BatteryStatus>> {
+    let mut statement = connection.prepare(
+        "
+        SELECT battery_voltage, reported_at
+        FROM telemetry
+        WHERE device = ?1 AND battery_voltage IS NOT NULL
+        ORDER BY reported_at DESC, id DESC
+        LIMIT ?2
+        ",
+    )?;
+    let mut readings: Vec<(u64, f64)> = statement
+        .query_map(params![device, DISCHARGE_REPORTS], |row| {
+            Ok((row.get(1)?, row.get(0)?))
+        })?
+        .collect::<Result<_, _>>()?;
+    readings.reverse();
+    let (reported_at, voltage) = match readings.last() {
+        Some(latest) => *latest,
+        None => return Ok(None),
+    };
+
+    let discharge_per_second = discharge_rate(&readings);
+    let warning_at = discharge_per_second.map(|rate| {
+        if voltage <= warning_voltage {
+            reported_at
+        } else {
+            reported_at + ((voltage - warning_voltage) / rate) as u64
+        }
+    });
+
+    Ok(Some(BatteryStatus {
+        voltage,
+        reported_at,
+        warning_voltage,
+        low: is_battery_low(voltage, warning_voltage),
+        discharge_per_day: discharge_per_second.map(|rate| rate * 86400.0),
+        warning_at,
+    }))
+}
+
+/// Volts per second lost since the battery has last been charged, fitted by
+/// linear regression through the readings, given as `(reported_at, voltage)`
+/// ordered by time. Returns `None`, if the readings span too short a time or
+/// the voltage is not dropping.
+fn discharge_rate(readings: &[(u64, f64)]) -> Option<f64> {
+    // Only readings after the last charge are part of the current discharge
+    let start = readings
+        .windows(2)
+        .rposition(|pair| pair[1].1 - pair[0].1 > CHARGE_DETECTION_VOLTAGE)
+        .map_or(0, |position| position + 1);
+    let readings = &readings[start..];
+
+    let (first, last) = (readings.first()?.0, readings.last()?.0);
+    if last - first < MIN_DISCHARGE_SPAN_SECONDS {
+        return None;
+    }
+
+    let count = readings.len() as f64;
+    let mean_time = readings.iter().map(|r| (r.0 - first) as f64).sum::<f64>() / count;
+    let mean_voltage = readings.iter().map(|r| r.1).sum::<f64>() / count;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for (time, voltage) in readings {
+        let time = (time - first) as f64 - mean_time;
+        covariance += time * (voltage - mean_voltage);
+        variance += time * time;
+    }
+
+    let slope = covariance / variance;
+    if slope >= 0.0 {
+        return None;
+    }
+    Some(-slope)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+
+    #[test]
+    fn ignores_missing_battery() {
+        assert!(is_battery_low(3.5, 3.6));
+        assert!(!is_battery_low(3.7, 3.6));
+        assert!(!is_battery_low(0.0, 3.6));
+    }
+
+    #[test]
+    fn estimates_discharge_rate() {
+        let readings: Vec<(u64, f64)> = (0..24)
+            .map(|hour| (hour * HOUR, 4.2 - hour as f64 * 0.01))
+            .collect();
+        let rate = discharge_rate(&readings).unwrap();
+        assert!((rate * HOUR as f64 - 0.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn ignores_readings_before_charging() {
+        let mut readings: Vec<(u64, f64)> = (0..24)
+            .map(|hour| (hour * HOUR, 3.8 - hour as f64 * 0.05))
+            .collect();
+        readings.extend((24..48).map(|hour| (hour * HOUR, 4.2 - (hour - 24) as f64 * 0.01)));
+        let rate = discharge_rate(&readings).unwrap();
+        assert!((rate * HOUR as f64 - 0.01).abs() < 1e-9);
+    }
+
+    #[test]
+    fn needs_a_dropping_voltage_over_time() {
+        assert_eq!(discharge_rate(&[]), None);
+        assert_eq!(discharge_rate(&[(0, 4.0), (HOUR / 2, 3.9)]), None);
+        assert_eq!(
+            discharge_rate(&[(0, 4.0), (HOUR, 4.0), (2 * HOUR, 4.05)]),
+            None
+        );
+    }
+}